@@ -1,15 +1,133 @@
-use std::{net::IpAddr, ops::Range, path::PathBuf};
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    ops::Range,
+    path::PathBuf,
+};
 
 use config::{Config, ConfigError, File};
+use ipnet::IpNet;
 use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
 use url::Url;
 
+/// Shortest node token and JWT secret accepted, so they can't be guessed.
+const MIN_SECRET_LENGTH: usize = 32;
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum FtpCredentialSettings {
+    /// Credentials are verified by the panel.
+    Panel,
+    /// Credentials are read from a JSON file mapping usernames to Argon2 hashes.
+    Local { path: PathBuf },
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FtpsClientAuth {
+    /// Client certificates are not asked for.
+    Off,
+    /// Client certificates are verified if the client sends one.
+    Request,
+    /// Clients must send a valid certificate.
+    Require,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FtpTlsSettings {
+    /// Path to the PEM encoded certificate chain.
+    pub certificate: PathBuf,
+    /// Path to the PEM encoded private key.
+    pub private_key: PathBuf,
+    /// Whether clients have to authenticate with a certificate.
+    pub client_auth: FtpsClientAuth,
+    /// PEM encoded CA certificates used to verify the client certificates.
+    pub client_ca: Option<PathBuf>,
+    /// Reject clients which don't secure the control channel, this includes the
+    /// password.
+    pub require_control_channel: bool,
+    /// Reject clients which don't secure the data channel.
+    pub require_data_channel: bool,
+    /// No of seconds between each check for changes of the certificate files.
+    pub reload_interval: u64,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FtpAuditSettings {
+    /// The directory the audit logs of the servers are written to.
+    pub directory: PathBuf,
+    /// Size in bytes after which the log of a server is rotated.
+    pub max_file_size: u64,
+    /// No of rotated logs kept for every server.
+    pub max_files: usize,
+    /// Whether the events are also sent to the panel.
+    pub forward_to_panel: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "source", rename_all = "snake_case")]
+pub enum FtpPassiveHost {
+    /// The address the client connected to, works when the node isn't behind NAT.
+    Connection,
+    /// A static public address.
+    Ip { address: Ipv4Addr },
+    /// A DNS name, resolved whenever a client enters passive mode.
+    Dns { name: String },
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FtpLimitSettings {
+    /// No of failed logins after which the address and the login are locked out.
+    pub max_failed_logins: u32,
+    /// No of seconds of the first lockout, doubled with every further failure.
+    pub lockout_base: u64,
+    /// Maximum no of seconds of a lockout. Failures are forgotten after this long.
+    pub lockout_max: u64,
+    /// Maximum no of sessions across all the users.
+    pub max_sessions: usize,
+    /// Maximum no of sessions of a single login.
+    pub max_sessions_per_user: usize,
+    /// No of seconds after which idle sessions are disconnected.
+    pub idle_timeout: u64,
+    /// If not empty, only these networks can log in.
+    #[serde(default)]
+    pub allow: Vec<IpNet>,
+    /// Networks which can never log in, even if allowed.
+    #[serde(default)]
+    pub deny: Vec<IpNet>,
+}
+
 #[derive(Debug, Deserialize, Clone)]
 pub struct FtpSettings {
     /// The port to listen for ftp connections.
     pub port: u16,
     /// The interface to listen on for ftp connection.
     pub interface: IpAddr,
+    /// Ports used for passive data connections. Must not overlap with the
+    /// container port range.
+    pub passive_ports: Range<u16>,
+    /// The address sent to clients entering passive mode.
+    pub passive_host: FtpPassiveHost,
+    /// The banner sent to clients when they connect.
+    pub greeting: String,
+    /// Where the ftp credentials are verified.
+    pub credentials: FtpCredentialSettings,
+    /// FTPS configuration. If left `None`, only plain FTP is supported.
+    pub tls: Option<FtpTlsSettings>,
+    /// Audit log of the logins and file operations, shared with SFTP.
+    pub audit: FtpAuditSettings,
+    /// Login and session limits, shared with SFTP.
+    pub limits: FtpLimitSettings,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct SftpSettings {
+    /// The port to listen for sftp connections.
+    pub port: u16,
+    /// The interface to listen on for sftp connections.
+    pub interface: IpAddr,
+    /// Path to the OpenSSH private key the server identifies itself with.
+    pub host_key: PathBuf,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,22 +136,150 @@ pub struct ApiSettings {
     pub port: u16,
     /// The interface to listen on for ftp connection.
     pub interface: IpAddr,
+    /// Token shared with the panel, sent as a bearer token or used to sign requests.
+    pub token: String,
+    /// No of seconds a signed request is accepted for, older ones are rejected so
+    /// they can't be replayed.
+    pub signature_max_age: u64,
+    /// Secret the panel signs the tokens of its users with, using HS256.
+    pub jwt_secret: String,
+    /// Largest recipe archive accepted in bytes.
+    pub max_recipe_size: usize,
+    /// Local copy of `redoc.standalone.js`, served with the API docs at `/docs`.
+    /// Without it, only `/openapi.json` is served.
+    pub docs_bundle: Option<PathBuf>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct PanelSettings {
     /// Panel Url
-    url: Url,
+    pub url: Url,
     /// Maxmum no of seconds to wait before retrying a request.
-    max_timeout: u8,
+    pub max_timeout: u8,
     /// Maximum no of times to retry sending a request before raising an exception.
-    max_retries: u8,
+    pub max_retries: u8,
+    /// No of seconds to wait for the connection to the panel.
+    pub connect_timeout: u64,
+    /// No of seconds to wait for the panel to answer a request, including the
+    /// connection. Retries are timed separately.
+    pub request_timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum NetworkIsolation {
+    /// Every server gets its own bridge network.
+    PerServer,
+    /// All servers share one bridge network with inter-container communication disabled.
+    Shared,
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct ContainerManagerSettings {
     /// Range of ports that can be allocated to containers
     pub container_port_range: Range<u16>,
+    /// How the containers are isolated from each other
+    pub network_isolation: NetworkIsolation,
+    /// The file where the egress policies of the containers are stored
+    pub egress_policy_file: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ImageGcSettings {
+    /// No of seconds between each garbage collection run.
+    pub interval: u64,
+    /// Only collect garbage when the images managed by mastiff take up more than
+    /// this many bytes. If left `None`, garbage is collected on every run.
+    pub disk_usage_threshold: Option<u64>,
+    /// Only report the images which would be removed, without removing them.
+    pub dry_run: bool,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuotaSettings {
+    /// The file where the disk limits of the servers are stored
+    pub limit_file: PathBuf,
+    /// No of seconds between each scan of the disk usage.
+    pub scan_interval: u64,
+    /// Stop servers using more than their limit times this ratio, eg. `1.5`. If left
+    /// `None`, servers over their limit are only refused uploads.
+    pub stop_ratio: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct FileManagerSettings {
+    /// Archives extracting to more than this many bytes are refused, the disk limit
+    /// of the server is also applied.
+    pub max_extracted_size: u64,
+    /// Archives with more entries than this are refused.
+    pub max_archive_entries: usize,
+    /// The directory where chunked uploads are kept until they are finished.
+    pub upload_directory: PathBuf,
+    /// No of seconds after the last chunk an unfinished upload is discarded.
+    pub upload_expiry: u64,
+    /// Uploads larger than this many bytes are refused when they are created.
+    pub max_upload_size: u64,
+    /// Maximum no of seconds a download link is valid for.
+    pub download_link_max_age: u64,
+    /// Files pulled from a url can't be larger than this many bytes, the disk limit
+    /// of the server is also applied.
+    pub max_pull_size: u64,
+    /// No of seconds a pull may take in total.
+    pub pull_timeout: u64,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    /// One line per event.
+    Text,
+    /// Several lines per event, easier to read while developing.
+    Pretty,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+/// Events of the life of a span, which can be logged like the events themselves.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SpanEvent {
+    New,
+    Enter,
+    Exit,
+    /// Also logs how long the span was open for.
+    Close,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogFileSettings {
+    /// The directory the logs are written to.
+    pub directory: PathBuf,
+    /// Name of the log files, followed by the date and time of the rotation.
+    pub prefix: String,
+    pub rotation: LogRotation,
+    /// No of files kept, the oldest are deleted. If left `None`, all are kept.
+    pub max_files: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LoggingSettings {
+    /// Which events are logged, in the syntax of `RUST_LOG`, eg.
+    /// `info,mastiff_backend::ftp=debug`. Can be changed at runtime through the API.
+    pub filter: String,
+    pub format: LogFormat,
+    /// If given, the logs are written to rotating files instead of stdout.
+    pub file: Option<LogFileSettings>,
+    /// The span events which are logged.
+    #[serde(default)]
+    pub span_events: Vec<SpanEvent>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -44,12 +290,23 @@ pub struct Settings {
     pub container_manager: ContainerManagerSettings,
     /// The path where all the recipes are stored.
     pub recipe_directory: PathBuf,
+    /// Recipe image garbage collection configuration
+    pub image_gc: ImageGcSettings,
+    /// Disk quota configuration
+    pub quota: QuotaSettings,
+    /// File manager configuration
+    pub file_manager: FileManagerSettings,
     /// FTP configuration
     pub ftp: FtpSettings,
+    /// SFTP configuration. Uses the same credentials as FTP, if left `None` the
+    /// SFTP server is not started.
+    pub sftp: Option<SftpSettings>,
     /// Panel configuration
     pub panel: PanelSettings,
     /// Rest API configuration
     pub rest_api: ApiSettings,
+    /// Logging configuration
+    pub logging: LoggingSettings,
 }
 
 impl Settings {
@@ -60,6 +317,63 @@ impl Settings {
             .build()?;
 
         tracing::debug!("Config loaded: {:#?}", config);
-        config.try_deserialize()
+        let settings: Settings = config.try_deserialize()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Err(e) = EnvFilter::try_new(&self.logging.filter) {
+            return Err(ConfigError::Message(format!(
+                "logging.filter is invalid: {e}"
+            )));
+        }
+
+        for (name, secret) in [
+            ("rest_api.token", &self.rest_api.token),
+            ("rest_api.jwt_secret", &self.rest_api.jwt_secret),
+        ] {
+            if secret.len() < MIN_SECRET_LENGTH {
+                return Err(ConfigError::Message(format!(
+                    "{name} must be at least {MIN_SECRET_LENGTH} characters long"
+                )));
+            }
+        }
+
+        // Used as the period of timers, which can't be 0. A session timing out right
+        // away would be disconnected before it could log in.
+        let mut intervals = vec![
+            ("image_gc.interval", self.image_gc.interval),
+            ("quota.scan_interval", self.quota.scan_interval),
+            ("ftp.limits.idle_timeout", self.ftp.limits.idle_timeout),
+        ];
+        if let Some(tls) = &self.ftp.tls {
+            intervals.push(("ftp.tls.reload_interval", tls.reload_interval));
+            if tls.client_auth != FtpsClientAuth::Off && tls.client_ca.is_none() {
+                return Err(ConfigError::Message(
+                    "ftp.tls.client_ca is required to verify client certificates".to_string(),
+                ));
+            }
+        }
+        if let Some((name, _)) = intervals.iter().find(|(_, interval)| *interval == 0) {
+            return Err(ConfigError::Message(format!("{name} must not be 0")));
+        }
+
+        let passive_ports = &self.ftp.passive_ports;
+        let container_ports = &self.container_manager.container_port_range;
+
+        if passive_ports.is_empty() {
+            return Err(ConfigError::Message(
+                "ftp.passive_ports must not be empty".to_string(),
+            ));
+        }
+        // Containers publishing a port from the passive range would steal the
+        // data connections.
+        if passive_ports.start < container_ports.end && container_ports.start < passive_ports.end {
+            return Err(ConfigError::Message(format!(
+                "ftp.passive_ports {passive_ports:?} overlaps with container_port_range {container_ports:?}"
+            )));
+        }
+        Ok(())
     }
 }
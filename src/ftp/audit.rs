@@ -0,0 +1,355 @@
+use std::{
+    io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use eyre::Result;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader, ReadBuf},
+    sync::mpsc,
+};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::{auth::User, storage::normalize};
+use crate::{config::FtpAuditSettings, panel::Panel};
+
+/// No of events waiting to be written before new ones are dropped.
+const QUEUE_SIZE: usize = 10_000;
+/// No of events sent to the panel in one request.
+const PANEL_BATCH_SIZE: usize = 100;
+/// No of events waiting to be forwarded before new ones are only written locally,
+/// so a slow panel can't hold up the local log.
+const PANEL_QUEUE_SIZE: usize = 10 * PANEL_BATCH_SIZE;
+/// Log of the failed logins which don't name an existing server. Not a valid server
+/// id, so it can't clash with the log of a server.
+const UNKNOWN_SERVER_LOG: &str = "@unknown";
+/// How often events are sent to the panel, if the batch isn't full before.
+const PANEL_FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    /// Too many failed logins, further logins are refused for a while.
+    LockedOut,
+    Upload,
+    Download,
+    Delete,
+    Rename,
+    CreateDir,
+    RemoveDir,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct AuditEvent {
+    /// Unix timestamp in milliseconds.
+    pub timestamp: u64,
+    pub username: String,
+    pub server_id: String,
+    #[schema(value_type = Option<String>)]
+    pub client_ip: Option<IpAddr>,
+    pub action: AuditAction,
+    /// Path relative to the server's data directory.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub path: Option<PathBuf>,
+    /// The new path of renamed files.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    #[schema(value_type = Option<String>)]
+    pub target: Option<PathBuf>,
+    /// No of bytes transferred by uploads and downloads.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+}
+
+impl AuditEvent {
+    pub fn new(
+        username: &str,
+        server_id: &str,
+        client_ip: Option<IpAddr>,
+        action: AuditAction,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+
+        Self {
+            timestamp,
+            username: username.to_string(),
+            server_id: server_id.to_string(),
+            client_ip,
+            action,
+            path: None,
+            target: None,
+            bytes: None,
+        }
+    }
+
+    pub fn for_user(user: &User, action: AuditAction) -> Self {
+        Self::new(user.username(), user.server_id(), user.client_ip(), action)
+    }
+
+    pub fn with_path<P: AsRef<Path>>(mut self, path: P) -> Self {
+        self.path = Some(Path::new("/").join(normalize(path.as_ref())));
+        self
+    }
+
+    pub fn with_target<P: AsRef<Path>>(mut self, target: P) -> Self {
+        self.target = Some(Path::new("/").join(normalize(target.as_ref())));
+        self
+    }
+
+    pub fn with_bytes(mut self, bytes: u64) -> Self {
+        self.bytes = Some(bytes);
+        self
+    }
+}
+
+/// Records the logins and file operations of the FTP and SFTP sessions to a
+/// rotating JSON lines file per server, optionally forwarding them to the panel.
+#[derive(Debug)]
+pub struct AuditLog {
+    settings: FtpAuditSettings,
+    sender: mpsc::Sender<AuditEvent>,
+}
+
+impl AuditLog {
+    /// Creates the log and spawns the task writing the events.
+    pub fn new(settings: FtpAuditSettings, panel: Arc<Panel>) -> Arc<Self> {
+        let (sender, receiver) = mpsc::channel(QUEUE_SIZE);
+        let forwarder = settings.forward_to_panel.then(|| {
+            let (sender, receiver) = mpsc::channel(PANEL_QUEUE_SIZE);
+            tokio::spawn(forward_events(receiver, panel));
+            sender
+        });
+        tokio::spawn(write_events(settings.clone(), receiver, forwarder));
+
+        Arc::new(Self { settings, sender })
+    }
+
+    /// Queues the event for writing. Never blocks, so it can be used when a
+    /// transfer is dropped. Events are dropped while the queue is full.
+    pub fn record(&self, event: AuditEvent) {
+        tracing::debug!("Audit: {event:?}");
+        let direction = match event.action {
+            AuditAction::Upload => Some("upload"),
+            AuditAction::Download => Some("download"),
+            _ => None,
+        };
+        if let (Some(direction), Some(bytes)) = (direction, event.bytes) {
+            metrics::counter!(
+                "mastiff_ftp_transferred_bytes_total",
+                "server_id" => event.server_id.clone(),
+                "direction" => direction
+            )
+            .increment(bytes);
+        }
+        match self.sender.try_send(event) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(event)) => {
+                tracing::warn!("The audit log queue is full, dropped {event:?}");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::error!("The audit log writer has stopped, event dropped");
+            }
+        }
+    }
+
+    /// Returns the events of a server, newest first. Only the events after `since`
+    /// are returned, if given.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn query(
+        &self,
+        server_id: &str,
+        since: Option<u64>,
+        limit: usize,
+    ) -> Result<Vec<AuditEvent>> {
+        let mut events = Vec::new();
+
+        // The current log first, then the rotated ones from newest to oldest.
+        for index in 0..=self.settings.max_files {
+            let path = log_path(&self.settings.directory, server_id, index);
+            let file = match fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+                Err(e) => return Err(e.into()),
+            };
+
+            let mut lines = BufReader::new(file).lines();
+            let mut file_events = Vec::new();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<AuditEvent>(&line) {
+                    Ok(event) => file_events.push(event),
+                    Err(e) => tracing::warn!("Skipping invalid line in {path:?}: {e}"),
+                }
+            }
+
+            for event in file_events.into_iter().rev() {
+                if since.is_some_and(|since| event.timestamp <= since) {
+                    return Ok(events);
+                }
+                events.push(event);
+                if events.len() >= limit {
+                    return Ok(events);
+                }
+            }
+        }
+
+        Ok(events)
+    }
+}
+
+/// `<server-id>.log` for the current log, `<server-id>.log.<n>` for the rotated ones.
+/// Events without a server go to the log of unknown servers.
+fn log_path(directory: &Path, server_id: &str, index: usize) -> PathBuf {
+    let server_id = match server_id {
+        "" => UNKNOWN_SERVER_LOG,
+        server_id => server_id,
+    };
+    match index {
+        0 => directory.join(format!("{server_id}.log")),
+        index => directory.join(format!("{server_id}.log.{index}")),
+    }
+}
+
+async fn write_events(
+    settings: FtpAuditSettings,
+    mut receiver: mpsc::Receiver<AuditEvent>,
+    forwarder: Option<mpsc::Sender<AuditEvent>>,
+) {
+    if let Err(e) = fs::create_dir_all(&settings.directory).await {
+        tracing::error!("Could not create the audit log directory: {e}");
+    }
+
+    while let Some(event) = receiver.recv().await {
+        if let Err(e) = append(&settings, &event).await {
+            tracing::error!(
+                "Could not write to the audit log of {}: {e}",
+                event.server_id
+            );
+        }
+        // Events the panel can't keep up with are only kept in the local log.
+        if let Some(forwarder) = &forwarder {
+            if let Err(mpsc::error::TrySendError::Full(event)) = forwarder.try_send(event) {
+                tracing::warn!("The panel is behind on audit events, not forwarding {event:?}");
+            }
+        }
+    }
+}
+
+/// Sends the events to the panel in batches.
+async fn forward_events(mut receiver: mpsc::Receiver<AuditEvent>, panel: Arc<Panel>) {
+    let mut batch = Vec::new();
+    let mut flush = tokio::time::interval(PANEL_FLUSH_INTERVAL);
+
+    loop {
+        tokio::select! {
+            event = receiver.recv() => {
+                let Some(event) = event else { break };
+                batch.push(event);
+                if batch.len() < PANEL_BATCH_SIZE {
+                    continue;
+                }
+            }
+            _ = flush.tick() => {}
+        }
+
+        if !batch.is_empty() {
+            // Events the panel didn't accept are only kept in the local log.
+            if let Err(e) = panel.send_ftp_audit_events(&batch).await {
+                tracing::error!("Could not forward {} audit events: {e}", batch.len());
+            }
+            batch.clear();
+        }
+    }
+}
+
+async fn append(settings: &FtpAuditSettings, event: &AuditEvent) -> Result<()> {
+    let path = log_path(&settings.directory, &event.server_id, 0);
+
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    if file.metadata().await?.len() >= settings.max_file_size {
+        drop(file);
+        rotate(settings, &event.server_id).await?;
+        file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .await?;
+    }
+
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    file.write_all(&line).await?;
+    Ok(())
+}
+
+/// Shifts every log of the server up by one, dropping the oldest.
+async fn rotate(settings: &FtpAuditSettings, server_id: &str) -> Result<()> {
+    for index in (0..settings.max_files).rev() {
+        let from = log_path(&settings.directory, server_id, index);
+        let to = log_path(&settings.directory, server_id, index + 1);
+        match fs::rename(&from, &to).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+    if settings.max_files == 0 {
+        fs::remove_file(log_path(&settings.directory, server_id, 0)).await?;
+    }
+    Ok(())
+}
+
+/// Counts the bytes of a download, which is recorded once the client is done.
+pub struct AuditedReader<R> {
+    inner: R,
+    bytes: u64,
+    event: Option<AuditEvent>,
+    audit: Arc<AuditLog>,
+}
+
+impl<R> AuditedReader<R> {
+    pub fn new(inner: R, event: AuditEvent, audit: Arc<AuditLog>) -> Self {
+        Self {
+            inner,
+            bytes: 0,
+            event: Some(event),
+            audit,
+        }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for AuditedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.bytes += (buf.filled().len() - filled) as u64;
+        poll
+    }
+}
+
+impl<R> Drop for AuditedReader<R> {
+    fn drop(&mut self) {
+        if let Some(event) = self.event.take() {
+            self.audit.record(event.with_bytes(self.bytes));
+        }
+    }
+}
@@ -1,16 +1,46 @@
 use std::{
+    collections::HashMap,
     fmt::{Debug, Display},
+    net::IpAddr,
     path::{Path, PathBuf},
+    sync::{Arc, OnceLock},
 };
 
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
 use libunftp::auth::{AuthenticationError, Authenticator, Credentials, UserDetail};
+use serde::Deserialize;
+use tokio::fs;
+
+use super::{
+    audit::{AuditAction, AuditEvent, AuditLog},
+    limits::{LoginGuard, Refusal, SessionPermit},
+    permissions::Permissions,
+};
+use crate::config::FtpLimitSettings;
+use crate::panel::{CredentialCheck, Panel};
+
+/// Longer logins of unknown servers are cut off in the audit log.
+const MAX_AUDITED_LOGIN: usize = 128;
 
 #[derive(Debug, thiserror::Error)]
 pub enum AuthError {
     #[error("An unexpected error occured while looking up user info.")]
     Unknown,
-    #[error("Only password based authentication is supported")]
+    #[error("Only password and public key based authentication is supported")]
     UnsupportedMethod,
+    #[error("Could not verify the credentials: {0}")]
+    Lookup(String),
+    #[error(transparent)]
+    Refused(#[from] Refusal),
+}
+
+impl From<eyre::Error> for AuthError {
+    fn from(value: eyre::Error) -> Self {
+        AuthError::Lookup(format!("{value:#}"))
+    }
 }
 
 impl From<AuthError> for AuthenticationError {
@@ -22,21 +52,60 @@ impl From<AuthError> for AuthenticationError {
 #[derive(Debug)]
 pub struct User {
     username: String,
+    server_id: String,
     root_path: PathBuf,
+    permissions: Permissions,
+    client_ip: Option<IpAddr>,
+    /// Counts the session against the limits for as long as the user is logged in.
+    session: Option<SessionPermit>,
 }
 
 impl User {
-    pub fn new<P: Into<PathBuf>>(username: &str, root_path: P) -> Self {
+    pub fn new<P: Into<PathBuf>>(
+        username: &str,
+        server_id: &str,
+        root_path: P,
+        permissions: Permissions,
+        client_ip: Option<IpAddr>,
+    ) -> Self {
         User {
             username: username.to_string(),
+            server_id: server_id.to_string(),
             root_path: root_path.into(),
+            permissions,
+            client_ip,
+            session: None,
         }
     }
+
+    fn with_session(mut self, session: SessionPermit) -> Self {
+        self.session = Some(session);
+        self
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// The server whose data directory the user has access to.
+    pub fn server_id(&self) -> &str {
+        &self.server_id
+    }
+
+    /// What the user is allowed to do in the server's data directory.
+    pub fn permissions(&self) -> &Permissions {
+        &self.permissions
+    }
+
+    /// The address the user connected from.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
 }
 
 impl Display for User {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.username)
+        write!(f, "{}.{}", self.username, self.server_id)
     }
 }
 
@@ -49,37 +118,318 @@ impl UserDetail for User {
         Some(&self.root_path)
     }
 }
+
+/// An entry of the local credential file.
+#[derive(Debug, Deserialize)]
+struct LocalCredential {
+    /// Argon2 hash of the password in the PHC string format.
+    password_hash: String,
+    /// Public keys in the OpenSSH format which can be used to log in over SFTP.
+    #[serde(default)]
+    public_keys: Vec<String>,
+    /// If left out, the user has full access to the server's data directory.
+    #[serde(default)]
+    permissions: Permissions,
+}
+
+/// What the user proves their identity with.
+#[derive(Debug, Clone, Copy)]
+enum Secret<'a> {
+    Password(&'a str),
+    /// A public key in the OpenSSH format.
+    PublicKey(&'a str),
+}
+
+/// Where the credentials are verified.
+#[derive(Debug)]
+pub enum CredentialStore {
+    Panel(Arc<Panel>),
+    /// A JSON file mapping `<user>.<server-id>` to its credentials. The file is read
+    /// on every login, so changes are picked up without a restart.
+    Local(PathBuf),
+}
+
+impl CredentialStore {
+    async fn verify(
+        &self,
+        username: &str,
+        server_id: &str,
+        secret: Secret<'_>,
+    ) -> Result<CredentialCheck, AuthError> {
+        match (self, secret) {
+            (CredentialStore::Panel(panel), Secret::Password(password)) => Ok(panel
+                .verify_ftp_credentials(username, server_id, password)
+                .await?),
+            (CredentialStore::Panel(panel), Secret::PublicKey(public_key)) => Ok(panel
+                .verify_ftp_public_key(username, server_id, public_key)
+                .await?),
+            (CredentialStore::Local(path), secret) => {
+                let data = fs::read(path).await.map_err(eyre::Error::from)?;
+                let mut credentials: HashMap<String, LocalCredential> =
+                    serde_json::from_slice(&data).map_err(eyre::Error::from)?;
+
+                let Some(credential) = credentials.remove(&format!("{username}.{server_id}"))
+                else {
+                    if let Secret::Password(password) = secret {
+                        verify_dummy_password(password).await;
+                    }
+                    return Ok(CredentialCheck::UnknownUser);
+                };
+
+                let password = match secret {
+                    Secret::Password(password) => password,
+                    Secret::PublicKey(public_key) => {
+                        let known = credential
+                            .public_keys
+                            .iter()
+                            .any(|key| same_public_key(key, public_key));
+                        return Ok(if known {
+                            CredentialCheck::Valid(credential.permissions)
+                        } else {
+                            CredentialCheck::BadPassword
+                        });
+                    }
+                };
+
+                let valid = verify_password(password, credential.password_hash).await?;
+                Ok(if valid {
+                    CredentialCheck::Valid(credential.permissions)
+                } else {
+                    CredentialCheck::BadPassword
+                })
+            }
+        }
+    }
+}
+
+async fn verify_password(password: &str, password_hash: String) -> Result<bool, AuthError> {
+    // Hashing is slow on purpose, keep it off the async workers.
+    let password = password.to_string();
+    let valid = tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&password_hash)
+            .map_err(|e| eyre::eyre!("Invalid password hash: {e}"))?;
+        Ok::<_, eyre::Error>(
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok(),
+        )
+    })
+    .await
+    .map_err(|_| AuthError::Unknown)??;
+    Ok(valid)
+}
+
+/// Verifies the password against a hash no password matches, so logins of unknown
+/// users take as long as the others and can't be told apart by their timing.
+async fn verify_dummy_password(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let password = password.to_string();
+    let _ = tokio::task::spawn_blocking(move || {
+        let dummy_hash = DUMMY_HASH.get_or_init(|| {
+            Argon2::default()
+                .hash_password(b"", &SaltString::generate(&mut OsRng))
+                .expect("Argon2 hashes with the default parameters")
+                .to_string()
+        });
+        if let Ok(hash) = PasswordHash::new(dummy_hash) {
+            let _ = Argon2::default().verify_password(password.as_bytes(), &hash);
+        }
+    })
+    .await;
+}
+
 #[derive(Debug)]
 pub struct AuthManager {
     container_root: PathBuf,
+    credentials: CredentialStore,
+    audit: Arc<AuditLog>,
+    guard: LoginGuard,
 }
 
 impl AuthManager {
-    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+    pub fn new<P: Into<PathBuf>>(
+        root: P,
+        credentials: CredentialStore,
+        audit: Arc<AuditLog>,
+        limits: FtpLimitSettings,
+    ) -> Self {
         AuthManager {
             container_root: root.into(),
+            credentials,
+            audit,
+            guard: LoginGuard::new(limits),
         }
     }
+
+    pub fn limits(&self) -> &FtpLimitSettings {
+        self.guard.settings()
+    }
+
+    pub fn active_sessions(&self) -> usize {
+        self.guard.active_sessions()
+    }
+
+    /// Authenticates a login of the form `<user>.<server-id>` with a password.
+    pub async fn authenticate_password(
+        &self,
+        login: &str,
+        password: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<User, AuthenticationError> {
+        self.authenticate_with(login, Secret::Password(password), client_ip)
+            .await
+    }
+
+    /// Authenticates a login of the form `<user>.<server-id>` with a public key in
+    /// the OpenSSH format.
+    pub async fn authenticate_public_key(
+        &self,
+        login: &str,
+        public_key: &str,
+        client_ip: Option<IpAddr>,
+    ) -> Result<User, AuthenticationError> {
+        self.authenticate_with(login, Secret::PublicKey(public_key), client_ip)
+            .await
+    }
+
+    async fn authenticate_with(
+        &self,
+        login: &str,
+        secret: Secret<'_>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<User, AuthenticationError> {
+        tracing::debug!("User: {} attempting to authenticate", login);
+
+        if let Err(refusal) = self.guard.check(login, client_ip) {
+            tracing::warn!("Refused login of {login} from {client_ip:?}: {refusal}");
+            return Err(AuthError::from(refusal).into());
+        }
+
+        let result = match self.verify_login(login, secret, client_ip).await {
+            Ok(user) => {
+                self.guard.record_success(login);
+                match self.guard.open_session(login) {
+                    Ok(session) => Ok(user.with_session(session)),
+                    Err(refusal) => {
+                        tracing::warn!("Refused login of {login}: {refusal}");
+                        Err(AuthError::from(refusal).into())
+                    }
+                }
+            }
+            Err(e @ (AuthenticationError::BadUser | AuthenticationError::BadPassword)) => {
+                if let Some(lockout) = self.guard.record_failure(login, client_ip) {
+                    tracing::warn!(
+                        "Locked out {login} and {client_ip:?} for {} seconds after repeated failed logins",
+                        lockout.as_secs()
+                    );
+                    self.record_login(login, client_ip, AuditAction::LockedOut)
+                        .await;
+                }
+                Err(e)
+            }
+            Err(e) => Err(e),
+        };
+
+        let action = match result {
+            Ok(_) => AuditAction::Login,
+            Err(_) => AuditAction::LoginFailed,
+        };
+        self.record_login(login, client_ip, action).await;
+        result
+    }
+
+    async fn verify_login(
+        &self,
+        login: &str,
+        secret: Secret<'_>,
+        client_ip: Option<IpAddr>,
+    ) -> Result<User, AuthenticationError> {
+        let Some((username, server_id)) = parse_username(login) else {
+            return Err(AuthenticationError::BadUser);
+        };
+
+        let root_path = self.container_root.join(server_id);
+        if !fs::try_exists(&root_path).await.unwrap_or(false) {
+            if let Secret::Password(password) = secret {
+                verify_dummy_password(password).await;
+            }
+            return Err(AuthenticationError::BadUser);
+        }
+
+        match self.credentials.verify(username, server_id, secret).await? {
+            CredentialCheck::Valid(permissions) => Ok(User::new(
+                username,
+                server_id,
+                root_path,
+                permissions,
+                client_ip,
+            )),
+            CredentialCheck::BadPassword => Err(AuthenticationError::BadPassword),
+            CredentialCheck::UnknownUser => Err(AuthenticationError::BadUser),
+        }
+    }
+
+    /// Audits a login attempt. Logins for servers which don't exist go to a shared
+    /// log, so they can't create log files.
+    async fn record_login(&self, login: &str, client_ip: Option<IpAddr>, action: AuditAction) {
+        let known_server = match parse_username(login) {
+            Some((username, server_id)) => fs::try_exists(self.container_root.join(server_id))
+                .await
+                .unwrap_or(false)
+                .then_some((username, server_id)),
+            None => None,
+        };
+        let event = match known_server {
+            Some((username, server_id)) => AuditEvent::new(username, server_id, client_ip, action),
+            None => {
+                let login: String = login.chars().take(MAX_AUDITED_LOGIN).collect();
+                AuditEvent::new(&login, "", client_ip, action)
+            }
+        };
+        self.audit.record(event);
+    }
+}
+
+/// Compares the key type and data of two OpenSSH public keys, ignoring the comments.
+fn same_public_key(a: &str, b: &str) -> bool {
+    let key = |key: &str| key.split_whitespace().take(2).collect::<Vec<_>>();
+    key(a) == key(b)
+}
+
+/// Splits a login of the form `<user>.<server-id>`. The server id becomes a directory
+/// name, so anything which could escape the container root is rejected.
+fn parse_username(login: &str) -> Option<(&str, &str)> {
+    let (username, server_id) = login.rsplit_once('.')?;
+
+    if username.is_empty() || !is_valid_server_id(server_id) {
+        return None;
+    }
+    Some((username, server_id))
+}
+
+/// Whether the id is safe to use as a file name.
+pub fn is_valid_server_id(server_id: &str) -> bool {
+    !server_id.is_empty()
+        && server_id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
 #[async_trait::async_trait]
 impl Authenticator<User> for AuthManager {
     async fn authenticate(
         &self,
-        username: &str,
+        login: &str,
         creds: &Credentials,
     ) -> Result<User, AuthenticationError> {
-        tracing::debug!("User: {} attempting to authenticate", username);
+        let Some(password) = &creds.password else {
+            // I have no idea what this is, but it works. thanks rust analyzer
+            return Err(Into::<AuthenticationError>::into(
+                AuthError::UnsupportedMethod,
+            ));
+        };
 
-        if let Some(password) = &creds.password {
-            return Ok(User {
-                username: "meower".to_string(),
-                root_path: self.container_root.join("anvesh"),
-            });
-        }
-        // I have no idea what this is, but it works. thanks rust analyzer
-        Err(Into::<AuthenticationError>::into(
-            AuthError::UnsupportedMethod,
-        ))
+        self.authenticate_password(login, password, Some(creds.source_ip))
+            .await
     }
 }
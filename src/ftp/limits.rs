@@ -0,0 +1,301 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+use crate::config::FtpLimitSettings;
+
+/// Why a login was refused regardless of its credentials.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum Refusal {
+    /// The address is not in the allow list or is in the deny list.
+    #[error("Logins from this address are not allowed")]
+    AddressDenied,
+    #[error("Locked out for {} more seconds", remaining.as_secs())]
+    LockedOut { remaining: Duration },
+    #[error("Too many active sessions")]
+    TooManySessions,
+}
+
+#[derive(Debug)]
+struct FailedLogins {
+    count: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct SessionCounts {
+    total: usize,
+    per_user: HashMap<String, usize>,
+}
+
+/// Keeps track of failed logins and active sessions, shared by FTP and SFTP.
+#[derive(Debug)]
+pub struct LoginGuard {
+    settings: FtpLimitSettings,
+    failures: Mutex<HashMap<String, FailedLogins>>,
+    sessions: Arc<Mutex<SessionCounts>>,
+}
+
+impl LoginGuard {
+    pub fn new(settings: FtpLimitSettings) -> Self {
+        Self {
+            settings,
+            failures: Mutex::new(HashMap::new()),
+            sessions: Arc::new(Mutex::new(SessionCounts::default())),
+        }
+    }
+
+    pub fn settings(&self) -> &FtpLimitSettings {
+        &self.settings
+    }
+
+    /// Checks the address and the lockouts of the address and the login.
+    pub fn check(&self, login: &str, client_ip: Option<IpAddr>) -> Result<(), Refusal> {
+        if let Some(ip) = client_ip {
+            let allowed = self.settings.allow.is_empty()
+                || self.settings.allow.iter().any(|net| net.contains(&ip));
+            let denied = self.settings.deny.iter().any(|net| net.contains(&ip));
+            if !allowed || denied {
+                return Err(Refusal::AddressDenied);
+            }
+        }
+
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+        for key in keys(login, client_ip) {
+            let locked_until = failures.get(&key).and_then(|entry| entry.locked_until);
+            if let Some(until) = locked_until.filter(|until| *until > now) {
+                return Err(Refusal::LockedOut {
+                    remaining: until - now,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a failed login. Returns the lockout if this failure started one.
+    pub fn record_failure(&self, login: &str, client_ip: Option<IpAddr>) -> Option<Duration> {
+        let now = Instant::now();
+        let reset_after = Duration::from_secs(self.settings.lockout_max);
+        let mut failures = self.failures.lock().unwrap();
+
+        // Forget the addresses and logins which behaved for a while.
+        failures.retain(|_, entry| now.duration_since(entry.last_failure) < reset_after);
+
+        let mut lockout = None;
+        for key in keys(login, client_ip) {
+            let entry = failures.entry(key).or_insert(FailedLogins {
+                count: 0,
+                last_failure: now,
+                locked_until: None,
+            });
+            entry.count += 1;
+            entry.last_failure = now;
+
+            if entry.count >= self.settings.max_failed_logins {
+                // Every failure after the limit doubles the lockout.
+                let exponent = entry.count - self.settings.max_failed_logins;
+                let seconds = self
+                    .settings
+                    .lockout_base
+                    .saturating_mul(2u64.saturating_pow(exponent))
+                    .min(self.settings.lockout_max);
+                let duration = Duration::from_secs(seconds);
+                entry.locked_until = Some(now + duration);
+                lockout = lockout.max(Some(duration));
+            }
+        }
+        lockout
+    }
+
+    /// Clears the failed logins of the login. The failures of the address are kept,
+    /// otherwise one valid account would allow guessing the passwords of the others.
+    pub fn record_success(&self, login: &str) {
+        self.failures.lock().unwrap().remove(&keys(login, None)[0]);
+    }
+
+    /// No of sessions open over FTP and SFTP.
+    pub fn active_sessions(&self) -> usize {
+        self.sessions.lock().unwrap().total
+    }
+
+    /// Reserves a session for the login, which is released when the permit is dropped.
+    pub fn open_session(&self, login: &str) -> Result<SessionPermit, Refusal> {
+        let mut sessions = self.sessions.lock().unwrap();
+        let user_sessions = sessions.per_user.get(login).copied().unwrap_or(0);
+
+        if sessions.total >= self.settings.max_sessions
+            || user_sessions >= self.settings.max_sessions_per_user
+        {
+            return Err(Refusal::TooManySessions);
+        }
+
+        sessions.total += 1;
+        sessions
+            .per_user
+            .insert(login.to_string(), user_sessions + 1);
+        Ok(SessionPermit {
+            login: login.to_string(),
+            sessions: Arc::clone(&self.sessions),
+        })
+    }
+}
+
+/// Failures are counted for the address and the login separately, so spraying
+/// passwords from many addresses locks the login, and trying many logins from one
+/// address locks the address.
+fn keys(login: &str, client_ip: Option<IpAddr>) -> Vec<String> {
+    let mut keys = vec![format!("login:{login}")];
+    if let Some(ip) = client_ip {
+        keys.push(format!("ip:{ip}"));
+    }
+    keys
+}
+
+/// An active session, counted against the limits until dropped.
+#[derive(Debug)]
+pub struct SessionPermit {
+    login: String,
+    sessions: Arc<Mutex<SessionCounts>>,
+}
+
+impl Drop for SessionPermit {
+    fn drop(&mut self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.total = sessions.total.saturating_sub(1);
+        if let Some(count) = sessions.per_user.get_mut(&self.login) {
+            *count -= 1;
+            if *count == 0 {
+                sessions.per_user.remove(&self.login);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn guard() -> LoginGuard {
+        LoginGuard::new(FtpLimitSettings {
+            max_failed_logins: 3,
+            lockout_base: 10,
+            lockout_max: 60,
+            max_sessions: 3,
+            max_sessions_per_user: 2,
+            idle_timeout: 600,
+            allow: Vec::new(),
+            deny: vec!["10.0.0.0/8".parse().unwrap()],
+        })
+    }
+
+    fn ip(address: &str) -> Option<IpAddr> {
+        Some(address.parse().unwrap())
+    }
+
+    fn is_locked_out(result: Result<(), Refusal>) -> bool {
+        matches!(result, Err(Refusal::LockedOut { .. }))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn address_is_locked_out() {
+        let guard = guard();
+        for login in ["a", "b", "c"] {
+            guard.record_failure(login, ip("192.0.2.1"));
+        }
+
+        assert!(is_locked_out(guard.check("d", ip("192.0.2.1"))));
+        assert!(guard.check("d", ip("192.0.2.2")).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn login_is_locked_out_from_every_address() {
+        let guard = guard();
+        for address in ["192.0.2.1", "192.0.2.2", "192.0.2.3"] {
+            guard.record_failure("a", ip(address));
+        }
+
+        assert!(is_locked_out(guard.check("a", ip("192.0.2.4"))));
+        assert!(guard.check("b", ip("192.0.2.4")).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn lockouts_double_up_to_the_maximum() {
+        let guard = guard();
+        guard.record_failure("a", None);
+        assert_eq!(guard.record_failure("a", None), None);
+
+        for seconds in [10, 20, 40, 60, 60] {
+            assert_eq!(
+                guard.record_failure("a", None),
+                Some(Duration::from_secs(seconds))
+            );
+        }
+
+        tokio::time::advance(Duration::from_secs(59)).await;
+        assert!(is_locked_out(guard.check("a", None)));
+        tokio::time::advance(Duration::from_secs(1)).await;
+        assert!(guard.check("a", None).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failures_are_forgotten() {
+        let guard = guard();
+        guard.record_failure("a", None);
+        guard.record_failure("a", None);
+
+        tokio::time::advance(Duration::from_secs(60)).await;
+        // Counted as the first failure again.
+        assert_eq!(guard.record_failure("a", None), None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn success_only_clears_the_login() {
+        let guard = guard();
+        for _ in 0..3 {
+            guard.record_failure("a", ip("192.0.2.1"));
+        }
+        guard.record_success("a");
+
+        assert!(guard.check("a", None).is_ok());
+        assert!(is_locked_out(guard.check("a", ip("192.0.2.1"))));
+    }
+
+    #[test]
+    fn denied_addresses_are_refused() {
+        let guard = guard();
+        assert_eq!(
+            guard.check("a", ip("10.1.2.3")),
+            Err(Refusal::AddressDenied)
+        );
+        assert!(guard.check("a", ip("192.0.2.1")).is_ok());
+    }
+
+    #[test]
+    fn sessions_are_capped() {
+        let guard = guard();
+        let first = guard.open_session("a").unwrap();
+        let _second = guard.open_session("a").unwrap();
+        assert_eq!(
+            guard.open_session("a").unwrap_err(),
+            Refusal::TooManySessions
+        );
+
+        let _third = guard.open_session("b").unwrap();
+        assert_eq!(
+            guard.open_session("c").unwrap_err(),
+            Refusal::TooManySessions
+        );
+        assert_eq!(guard.active_sessions(), 3);
+
+        drop(first);
+        assert_eq!(guard.active_sessions(), 2);
+        assert!(guard.open_session("a").is_ok());
+    }
+}
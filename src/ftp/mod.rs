@@ -1,33 +1,371 @@
-// TODO: Switch to actor pattern. Implement audit logs.
+pub mod audit;
 pub mod auth;
-use std::{path::PathBuf, sync::Arc};
+pub mod limits;
+pub mod permissions;
+pub mod sftp;
+pub mod storage;
+pub mod tls;
+use std::{
+    path::PathBuf,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use libunftp::ServerBuilder;
+use eyre::{bail, eyre, Result};
+use libunftp::{
+    options::{self, FtpsRequired, Shutdown},
+    ServerBuilder,
+};
+use serde::Serialize;
+use tokio::sync::{mpsc, oneshot, watch};
 use tracing::instrument;
-use unftp_sbe_fs::Filesystem;
-
-use crate::config::FtpSettings;
-
-#[instrument(level = "DEBUG", skip(root_path))]
-pub async fn start_ftp_server(settings: FtpSettings, root_path: impl Into<PathBuf>) {
-    let root_path = root_path.into();
-    let authenticator = Arc::new(auth::AuthManager::new(&root_path));
-
-    let server = ServerBuilder::with_authenticator(
-        Box::new(move || Filesystem::new(root_path.clone())),
-        authenticator,
-    )
-    .build()
-    .await
-    .unwrap();
-
-    tracing::info!(
-        "FTPS server alive at {}:{}",
-        settings.interface,
-        settings.port
-    );
-    server
-        .listen(format!("{}:{}", settings.interface, settings.port))
-        .await
-        .unwrap();
+use utoipa::ToSchema;
+
+use crate::{
+    config::{FtpCredentialSettings, FtpPassiveHost, FtpSettings, FtpTlsSettings},
+    managers::quota::QuotaManager,
+    panel::Panel,
+};
+
+/// How long active sessions are given to finish when the server is stopped or
+/// restarted.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+/// Wait before the first restart after a failure, doubled with every further one.
+const RESTART_BACKOFF_BASE: Duration = Duration::from_secs(1);
+const RESTART_BACKOFF_MAX: Duration = Duration::from_secs(60);
+
+/// Builds the authenticator shared by the FTP and the SFTP server.
+pub fn build_authenticator(
+    settings: &FtpSettings,
+    root_path: impl Into<PathBuf>,
+    panel: Arc<Panel>,
+    audit: Arc<audit::AuditLog>,
+) -> Arc<auth::AuthManager> {
+    let credentials = match &settings.credentials {
+        FtpCredentialSettings::Panel => auth::CredentialStore::Panel(panel),
+        FtpCredentialSettings::Local { path } => auth::CredentialStore::Local(path.clone()),
+    };
+    Arc::new(auth::AuthManager::new(
+        root_path,
+        credentials,
+        audit,
+        settings.limits.clone(),
+    ))
+}
+
+/// The state of the FTP server, as reported to the API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum FtpHealth {
+    Starting,
+    Running {
+        address: String,
+        tls: bool,
+        /// Unix timestamp of when the server started listening.
+        since: u64,
+    },
+    /// The server failed and is restarted after `retry_in` seconds.
+    Failed {
+        error: String,
+        attempt: u32,
+        retry_in: u64,
+    },
+    Stopped,
+}
+
+#[derive(Debug)]
+enum Command {
+    Start,
+    Stop,
+    Restart,
+    Reload(Box<FtpSettings>),
+}
+
+/// What the supervisor does once the listener has shut down.
+enum Next {
+    Listen,
+    Stop,
+    Exit,
+}
+
+/// Controls the FTP server running in the background. The server is restarted
+/// with a back-off when it fails, eg. because the port is taken.
+#[derive(Debug)]
+pub struct FtpHandle {
+    commands: mpsc::Sender<Command>,
+    health: watch::Receiver<FtpHealth>,
+}
+
+impl FtpHandle {
+    /// Spawns the supervisor, which starts the server right away.
+    pub fn spawn(
+        settings: FtpSettings,
+        root_path: impl Into<PathBuf>,
+        authenticator: Arc<auth::AuthManager>,
+        audit: Arc<audit::AuditLog>,
+        quota: Arc<QuotaManager>,
+    ) -> Self {
+        let (commands, receiver) = mpsc::channel(8);
+        let (health, health_receiver) = watch::channel(FtpHealth::Starting);
+
+        let supervisor = FtpSupervisor {
+            greeting: leak_greeting(&settings),
+            settings,
+            root_path: root_path.into(),
+            authenticator,
+            audit,
+            quota,
+            commands: receiver,
+            health,
+        };
+        tokio::spawn(supervisor.run());
+
+        Self {
+            commands,
+            health: health_receiver,
+        }
+    }
+
+    pub async fn start(&self) -> Result<()> {
+        self.send(Command::Start).await
+    }
+
+    /// Stops accepting connections, active sessions are given a grace period.
+    pub async fn stop(&self) -> Result<()> {
+        self.send(Command::Stop).await
+    }
+
+    pub async fn restart(&self) -> Result<()> {
+        self.send(Command::Restart).await
+    }
+
+    /// Restarts the server with new settings. The credentials and limits are only
+    /// read on startup of the node.
+    pub async fn reload(&self, settings: FtpSettings) -> Result<()> {
+        self.send(Command::Reload(Box::new(settings))).await
+    }
+
+    pub fn health(&self) -> FtpHealth {
+        self.health.borrow().clone()
+    }
+
+    async fn send(&self, command: Command) -> Result<()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| eyre!("The FTP supervisor is not running"))
+    }
+}
+
+struct FtpSupervisor {
+    settings: FtpSettings,
+    /// libunftp needs a static greeting, it is only leaked again if it changes.
+    greeting: &'static str,
+    root_path: PathBuf,
+    authenticator: Arc<auth::AuthManager>,
+    audit: Arc<audit::AuditLog>,
+    quota: Arc<QuotaManager>,
+    commands: mpsc::Receiver<Command>,
+    health: watch::Sender<FtpHealth>,
+}
+
+impl FtpSupervisor {
+    #[instrument(level = "DEBUG", skip(self))]
+    async fn run(mut self) {
+        let mut running = true;
+        let mut attempt = 0;
+
+        loop {
+            if !running {
+                self.health.send_replace(FtpHealth::Stopped);
+                match self.commands.recv().await {
+                    None => return,
+                    Some(Command::Start | Command::Restart) => running = true,
+                    Some(Command::Reload(settings)) => self.apply(*settings),
+                    Some(Command::Stop) => {}
+                }
+                continue;
+            }
+
+            self.health.send_replace(FtpHealth::Starting);
+            match self.listen().await {
+                Ok(Next::Listen) => attempt = 0,
+                Ok(Next::Stop) => {
+                    attempt = 0;
+                    running = false;
+                }
+                Ok(Next::Exit) => return,
+                Err(e) => {
+                    attempt += 1;
+                    let retry_in = RESTART_BACKOFF_BASE
+                        .saturating_mul(2u32.saturating_pow(attempt - 1))
+                        .min(RESTART_BACKOFF_MAX);
+                    tracing::error!(
+                        "FTP server failed: {e:#}, restarting in {} seconds",
+                        retry_in.as_secs()
+                    );
+                    self.health.send_replace(FtpHealth::Failed {
+                        error: format!("{e:#}"),
+                        attempt,
+                        retry_in: retry_in.as_secs(),
+                    });
+
+                    // Keep listening to commands while waiting.
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry_in) => {}
+                        command = self.commands.recv() => match command {
+                            None => return,
+                            Some(Command::Start | Command::Restart) => {}
+                            Some(Command::Reload(settings)) => self.apply(*settings),
+                            Some(Command::Stop) => running = false,
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Runs the server until it is told otherwise. Renewed certificates are loaded
+    /// while it runs, without dropping any session.
+    async fn listen(&mut self) -> Result<Next> {
+        let resolver = self
+            .settings
+            .tls
+            .as_ref()
+            .map(tls::CertificateResolver::load)
+            .transpose()?;
+        if let (Some(settings), Some(resolver)) = (&self.settings.tls, &resolver) {
+            tokio::spawn(tls::watch_certificates(
+                settings.clone(),
+                Arc::downgrade(resolver),
+            ));
+        }
+
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+        let server = self
+            .builder(resolver)?
+            .shutdown_indicator(async move {
+                let _ = shutdown_rx.await;
+                Shutdown::new().grace_period(SHUTDOWN_GRACE_PERIOD)
+            })
+            .build()
+            .await?;
+
+        let address = format!("{}:{}", self.settings.interface, self.settings.port);
+        let listener = server.listen(address.clone());
+        tokio::pin!(listener);
+
+        let protocol = if self.settings.tls.is_some() {
+            "FTPS"
+        } else {
+            "FTP"
+        };
+        tracing::info!("{protocol} server alive at {address}");
+        self.health.send_replace(FtpHealth::Running {
+            address,
+            tls: self.settings.tls.is_some(),
+            since: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+
+        let next = loop {
+            tokio::select! {
+                result = &mut listener => {
+                    result?;
+                    bail!("The listener stopped unexpectedly");
+                }
+                command = self.commands.recv() => match command {
+                    None => break Next::Exit,
+                    Some(Command::Start) => {}
+                    Some(Command::Stop) => break Next::Stop,
+                    Some(Command::Restart) => break Next::Listen,
+                    Some(Command::Reload(settings)) => {
+                        self.apply(*settings);
+                        break Next::Listen;
+                    }
+                },
+            }
+        };
+
+        let _ = shutdown_tx.send(());
+        listener.await?;
+        Ok(next)
+    }
+
+    fn apply(&mut self, settings: FtpSettings) {
+        if settings.greeting != self.settings.greeting {
+            self.greeting = leak_greeting(&settings);
+        }
+        self.settings = settings;
+    }
+
+    fn builder(
+        &self,
+        resolver: Option<Arc<tls::CertificateResolver>>,
+    ) -> Result<ServerBuilder<storage::JailedFilesystem, auth::User>> {
+        let root_path = self.root_path.clone();
+        let audit = Arc::clone(&self.audit);
+        let quota = Arc::clone(&self.quota);
+        let passive_ports = self.settings.passive_ports.start..=self.settings.passive_ports.end - 1;
+
+        let builder = ServerBuilder::with_authenticator(
+            Box::new(move || {
+                storage::JailedFilesystem::new(
+                    root_path.clone(),
+                    Arc::clone(&audit),
+                    Arc::clone(&quota),
+                )
+            }),
+            Arc::clone(&self.authenticator),
+        )
+        .greeting(self.greeting)
+        .passive_ports(passive_ports)
+        .passive_host(passive_host(&self.settings.passive_host))
+        .idle_session_timeout(self.settings.limits.idle_timeout);
+
+        match (&self.settings.tls, resolver) {
+            (Some(tls), Some(resolver)) => configure_tls(builder, tls, resolver),
+            _ => Ok(builder),
+        }
+    }
+}
+
+fn leak_greeting(settings: &FtpSettings) -> &'static str {
+    Box::leak(settings.greeting.clone().into_boxed_str())
+}
+
+fn passive_host(host: &FtpPassiveHost) -> options::PassiveHost {
+    match host {
+        FtpPassiveHost::Connection => options::PassiveHost::FromConnection,
+        FtpPassiveHost::Ip { address } => options::PassiveHost::Ip(*address),
+        FtpPassiveHost::Dns { name } => options::PassiveHost::Dns(name.clone()),
+    }
+}
+
+fn configure_tls<S, U>(
+    builder: ServerBuilder<S, U>,
+    tls: &FtpTlsSettings,
+    resolver: Arc<tls::CertificateResolver>,
+) -> Result<ServerBuilder<S, U>>
+where
+    S: libunftp::storage::StorageBackend<U> + 'static,
+    U: libunftp::auth::UserDetail + 'static,
+{
+    let required = |required: bool| {
+        if required {
+            FtpsRequired::All
+        } else {
+            FtpsRequired::None
+        }
+    };
+    // The certificates and the client verification are set up in the rustls config.
+    Ok(builder
+        .ftps_manual(tls::server_config(tls, resolver)?)
+        .ftps_required(
+            required(tls.require_control_channel),
+            required(tls.require_data_channel),
+        ))
 }
@@ -0,0 +1,154 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use super::storage::normalize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    /// Download files and list directories.
+    Read,
+    /// Upload and overwrite files.
+    Write,
+    /// Delete files and directories.
+    Delete,
+    CreateDir,
+    /// Rename or move files and directories.
+    Rename,
+}
+
+impl Permission {
+    pub const ALL: [Permission; 5] = [
+        Permission::Read,
+        Permission::Write,
+        Permission::Delete,
+        Permission::CreateDir,
+        Permission::Rename,
+    ];
+}
+
+/// Overrides the permissions for a directory and everything below it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PathRule {
+    /// Path relative to the server's data directory.
+    pub path: PathBuf,
+    pub permissions: HashSet<Permission>,
+}
+
+/// What a user is allowed to do in a server's data directory.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Permissions {
+    /// Permissions for the paths which are not covered by any rule.
+    pub default: HashSet<Permission>,
+    /// The rule with the most specific path matching the accessed path is used.
+    #[serde(default)]
+    pub rules: Vec<PathRule>,
+}
+
+impl Permissions {
+    /// Full access to the whole data directory, used for users without any
+    /// permissions configured.
+    pub fn all() -> Self {
+        Self {
+            default: Permission::ALL.into_iter().collect(),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Only downloading and listing, used when the panel doesn't say what a user is
+    /// allowed to do.
+    pub fn read_only() -> Self {
+        Self {
+            default: HashSet::from([Permission::Read]),
+            rules: Vec::new(),
+        }
+    }
+
+    /// Whether the permission is granted for `path`, which must be relative to the
+    /// server's data directory and already normalized.
+    pub fn allows(&self, path: &Path, permission: Permission) -> bool {
+        self.rules
+            .iter()
+            .map(|rule| (normalize(&rule.path), &rule.permissions))
+            .filter(|(rule_path, _)| path.starts_with(rule_path))
+            .max_by_key(|(rule_path, _)| rule_path.components().count())
+            .map_or(&self.default, |(_, permissions)| permissions)
+            .contains(&permission)
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(path: &str, permissions: &[Permission]) -> PathRule {
+        PathRule {
+            path: PathBuf::from(path),
+            permissions: permissions.iter().copied().collect(),
+        }
+    }
+
+    fn permissions() -> Permissions {
+        Permissions {
+            default: HashSet::from([Permission::Read]),
+            rules: vec![
+                rule("plugins", &[Permission::Read, Permission::Write]),
+                rule("/plugins/../plugins/secret/", &[]),
+                rule("world", &Permission::ALL),
+            ],
+        }
+    }
+
+    #[test]
+    fn paths_without_a_rule_use_the_default() {
+        let permissions = permissions();
+        assert!(permissions.allows(Path::new(""), Permission::Read));
+        assert!(permissions.allows(Path::new("server.properties"), Permission::Read));
+        assert!(!permissions.allows(Path::new("server.properties"), Permission::Write));
+    }
+
+    #[test]
+    fn rules_cover_everything_below_their_path() {
+        let permissions = permissions();
+        assert!(permissions.allows(Path::new("plugins"), Permission::Write));
+        assert!(permissions.allows(Path::new("plugins/a/b.jar"), Permission::Write));
+        assert!(!permissions.allows(Path::new("plugins/a/b.jar"), Permission::Delete));
+        assert!(permissions.allows(Path::new("world/region"), Permission::Delete));
+    }
+
+    #[test]
+    fn rules_only_match_whole_components() {
+        let permissions = permissions();
+        assert!(!permissions.allows(Path::new("plugins-old"), Permission::Write));
+        assert!(!permissions.allows(Path::new("world2/level.dat"), Permission::Delete));
+    }
+
+    #[test]
+    fn the_most_specific_rule_wins() {
+        let permissions = permissions();
+        assert!(!permissions.allows(Path::new("plugins/secret"), Permission::Read));
+        assert!(!permissions.allows(Path::new("plugins/secret/key"), Permission::Read));
+        assert!(permissions.allows(Path::new("plugins/public"), Permission::Read));
+    }
+
+    #[test]
+    fn presets() {
+        for permission in Permission::ALL {
+            assert!(Permissions::all().allows(Path::new("a/b"), permission));
+            assert_eq!(
+                Permissions::read_only().allows(Path::new("a/b"), permission),
+                permission == Permission::Read
+            );
+        }
+    }
+}
@@ -0,0 +1,648 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::SeekFrom,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+use eyre::{eyre, Result, WrapErr};
+
+use libunftp::storage::{self, ErrorKind, Metadata, StorageBackend};
+use russh::{
+    keys::PublicKey,
+    server::{Auth, Config, Handler, Msg, Server, Session},
+    Channel, ChannelId,
+};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle, Name, OpenFlags, Status, StatusCode,
+};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::instrument;
+
+use super::{
+    audit::{AuditAction, AuditEvent, AuditLog},
+    auth::{AuthManager, User},
+    storage::JailedFilesystem,
+    RESTART_BACKOFF_BASE, RESTART_BACKOFF_MAX,
+};
+use crate::{config::SftpSettings, managers::quota::QuotaManager};
+
+/// Most entries returned by a single `readdir`, so large directories fit in the
+/// packets clients accept. They fetch the rest with further calls.
+const READDIR_PAGE_SIZE: usize = 100;
+
+/// Runs the SFTP server, restarting it with a back-off when it fails like the FTP
+/// server.
+pub async fn supervise_sftp_server(
+    settings: SftpSettings,
+    root_path: PathBuf,
+    authenticator: Arc<AuthManager>,
+    audit: Arc<AuditLog>,
+    quota: Arc<QuotaManager>,
+) {
+    let mut attempt = 0;
+    loop {
+        let started = Instant::now();
+        let result = start_sftp_server(
+            settings.clone(),
+            root_path.clone(),
+            Arc::clone(&authenticator),
+            Arc::clone(&audit),
+            Arc::clone(&quota),
+        )
+        .await;
+        let error = match result {
+            Ok(()) => eyre!("The listener stopped unexpectedly"),
+            Err(e) => e,
+        };
+
+        // A server which ran for a while failed for a new reason.
+        if started.elapsed() > RESTART_BACKOFF_MAX {
+            attempt = 0;
+        }
+        attempt += 1;
+        let retry_in = RESTART_BACKOFF_BASE
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(RESTART_BACKOFF_MAX);
+        tracing::error!(
+            "SFTP server failed: {error:#}, restarting in {} seconds",
+            retry_in.as_secs()
+        );
+        tokio::time::sleep(retry_in).await;
+    }
+}
+
+#[instrument(level = "DEBUG", skip(root_path, authenticator, audit, quota))]
+pub async fn start_sftp_server(
+    settings: SftpSettings,
+    root_path: impl Into<PathBuf>,
+    authenticator: Arc<AuthManager>,
+    audit: Arc<AuditLog>,
+    quota: Arc<QuotaManager>,
+) -> Result<()> {
+    let host_key = russh::keys::load_secret_key(&settings.host_key, None)
+        .wrap_err_with(|| format!("Could not load {}", settings.host_key.display()))?;
+    let config = Config {
+        inactivity_timeout: Some(Duration::from_secs(authenticator.limits().idle_timeout)),
+        auth_rejection_time: Duration::from_secs(3),
+        auth_rejection_time_initial: Some(Duration::from_secs(0)),
+        keys: vec![host_key],
+        ..Default::default()
+    };
+
+    let mut server = SftpServer {
+        root_path: root_path.into(),
+        authenticator,
+        audit,
+        quota,
+    };
+
+    tracing::info!(
+        "SFTP server alive at {}:{}",
+        settings.interface,
+        settings.port
+    );
+    server
+        .run_on_address(Arc::new(config), (settings.interface, settings.port))
+        .await?;
+    Ok(())
+}
+
+#[derive(Clone)]
+struct SftpServer {
+    root_path: PathBuf,
+    authenticator: Arc<AuthManager>,
+    audit: Arc<AuditLog>,
+    quota: Arc<QuotaManager>,
+}
+
+impl Server for SftpServer {
+    type Handler = SshSession;
+
+    fn new_client(&mut self, peer: Option<SocketAddr>) -> Self::Handler {
+        tracing::debug!("New SFTP connection from {peer:?}");
+        SshSession {
+            root_path: self.root_path.clone(),
+            authenticator: Arc::clone(&self.authenticator),
+            audit: Arc::clone(&self.audit),
+            quota: Arc::clone(&self.quota),
+            client_ip: peer.map(|peer| peer.ip()),
+            user: None,
+            channels: HashMap::new(),
+        }
+    }
+}
+
+/// An SSH connection. Only the `sftp` subsystem is served.
+struct SshSession {
+    root_path: PathBuf,
+    authenticator: Arc<AuthManager>,
+    audit: Arc<AuditLog>,
+    quota: Arc<QuotaManager>,
+    client_ip: Option<IpAddr>,
+    user: Option<Arc<User>>,
+    channels: HashMap<ChannelId, Channel<Msg>>,
+}
+
+impl SshSession {
+    fn accept(&mut self, user: Result<User, libunftp::auth::AuthenticationError>) -> Auth {
+        match user {
+            Ok(user) => {
+                tracing::info!("{user} logged in over SFTP");
+                self.user = Some(Arc::new(user));
+                Auth::Accept
+            }
+            Err(e) => {
+                tracing::debug!("SFTP authentication failed: {e}");
+                Auth::reject()
+            }
+        }
+    }
+}
+
+impl Handler for SshSession {
+    type Error = eyre::Error;
+
+    async fn auth_password(&mut self, login: &str, password: &str) -> eyre::Result<Auth> {
+        let user = self
+            .authenticator
+            .authenticate_password(login, password, self.client_ip)
+            .await;
+        Ok(self.accept(user))
+    }
+
+    async fn auth_publickey(&mut self, login: &str, public_key: &PublicKey) -> eyre::Result<Auth> {
+        let user = self
+            .authenticator
+            .authenticate_public_key(login, &public_key.to_openssh()?, self.client_ip)
+            .await;
+        Ok(self.accept(user))
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        _session: &mut Session,
+    ) -> eyre::Result<bool> {
+        self.channels.insert(channel.id(), channel);
+        Ok(true)
+    }
+
+    async fn channel_eof(&mut self, channel: ChannelId, session: &mut Session) -> eyre::Result<()> {
+        session.close(channel)?;
+        Ok(())
+    }
+
+    async fn subsystem_request(
+        &mut self,
+        channel_id: ChannelId,
+        name: &str,
+        session: &mut Session,
+    ) -> eyre::Result<()> {
+        let (Some(channel), Some(user), "sftp") =
+            (self.channels.remove(&channel_id), self.user.clone(), name)
+        else {
+            session.channel_failure(channel_id)?;
+            return Ok(());
+        };
+
+        session.channel_success(channel_id)?;
+        let sftp = SftpSession {
+            user,
+            quota: Arc::clone(&self.quota),
+            storage: JailedFilesystem::new(
+                &self.root_path,
+                Arc::clone(&self.audit),
+                Arc::clone(&self.quota),
+            )
+            .without_transfer_audit(),
+            handles: HashMap::new(),
+            next_handle: 0,
+        };
+        russh_sftp::server::run(channel.into_stream(), sftp).await;
+        Ok(())
+    }
+}
+
+enum OpenHandle {
+    /// The entries are listed by the first `readdir`, and handed out over the
+    /// following ones.
+    Dir {
+        path: String,
+        entries: Option<VecDeque<File>>,
+    },
+    /// Files are audited as a single transfer when the handle is closed.
+    File {
+        path: String,
+        /// Open while the file is written, so writes land at their offset without
+        /// touching the rest of the file.
+        upload: Option<fs::File>,
+        transferred: u64,
+    },
+}
+
+/// Serves the SFTP requests of a session through the same jailed storage as FTP.
+struct SftpSession {
+    user: Arc<User>,
+    quota: Arc<QuotaManager>,
+    storage: JailedFilesystem,
+    handles: HashMap<String, OpenHandle>,
+    next_handle: u64,
+}
+
+impl SftpSession {
+    fn open_handle(&mut self, handle: OpenHandle) -> String {
+        self.next_handle += 1;
+        let id = self.next_handle.to_string();
+        self.handles.insert(id.clone(), handle);
+        id
+    }
+
+    fn file_path(&self, handle: &str) -> Result<String, StatusCode> {
+        match self.handles.get(handle) {
+            Some(OpenHandle::File { path, .. }) => Ok(path.clone()),
+            _ => Err(StatusCode::Failure),
+        }
+    }
+
+    fn handle_path(&self, handle: &str) -> Result<String, StatusCode> {
+        match self.handles.get(handle) {
+            Some(OpenHandle::File { path, .. } | OpenHandle::Dir { path, .. }) => Ok(path.clone()),
+            None => Err(StatusCode::Failure),
+        }
+    }
+
+    fn add_transferred(&mut self, handle: &str, bytes: u64) {
+        if let Some(OpenHandle::File { transferred, .. }) = self.handles.get_mut(handle) {
+            *transferred += bytes;
+        }
+    }
+
+    async fn attributes(&self, path: &str) -> Result<FileAttributes, StatusCode> {
+        let meta = self
+            .storage
+            .metadata(&self.user, path)
+            .await
+            .map_err(status)?;
+        Ok(attributes(&meta))
+    }
+
+    async fn list_dir(&self, path: String) -> Result<VecDeque<File>, StatusCode> {
+        Ok(self
+            .storage
+            .list(&self.user, path)
+            .await
+            .map_err(status)?
+            .into_iter()
+            .map(|info| {
+                let name = Path::new(&info.path)
+                    .file_name()
+                    .map(|name| name.to_string_lossy().to_string())
+                    .unwrap_or_default();
+                File::new(name, attributes(&info.metadata))
+            })
+            .collect())
+    }
+}
+
+fn ok_status(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn attributes<M: Metadata>(meta: &M) -> FileAttributes {
+    let mut attrs = FileAttributes {
+        size: Some(meta.len()),
+        uid: Some(meta.uid()),
+        gid: Some(meta.gid()),
+        permissions: Some(meta.permissions().0 & 0o777),
+        mtime: meta
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|time| time.as_secs() as u32),
+        ..FileAttributes::empty()
+    };
+    attrs.set_dir(meta.is_dir());
+    attrs.set_regular(meta.is_file());
+    attrs.set_symlink(meta.is_symlink());
+    attrs
+}
+
+fn status(error: storage::Error) -> StatusCode {
+    match error.kind() {
+        ErrorKind::PermanentFileNotAvailable
+        | ErrorKind::TransientFileNotAvailable
+        | ErrorKind::PermanentDirectoryNotAvailable => StatusCode::NoSuchFile,
+        ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// Resolves the path the same way as the jail, so clients see paths rooted at the
+/// server's data directory.
+fn resolve(path: &str) -> String {
+    let mut resolved = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                resolved.pop();
+            }
+            part => resolved.push(part),
+        }
+    }
+    format!("/{}", resolved.join("/"))
+}
+
+impl russh_sftp::server::Handler for SftpSession {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        _attrs: FileAttributes,
+    ) -> Result<Handle, Self::Error> {
+        let mut upload = None;
+        if pflags.contains(OpenFlags::WRITE) {
+            let mut options = fs::OpenOptions::new();
+            options
+                .write(true)
+                .create(pflags.contains(OpenFlags::CREATE))
+                .create_new(pflags.contains(OpenFlags::EXCLUDE))
+                .truncate(pflags.contains(OpenFlags::TRUNCATE));
+            let file = self
+                .storage
+                .open_for_write(&self.user, &filename, &options)
+                .await
+                .map_err(status)?;
+            upload = Some(file);
+        } else {
+            let meta = self
+                .storage
+                .metadata(&self.user, &filename)
+                .await
+                .map_err(status)?;
+            if !meta.is_file() {
+                return Err(StatusCode::Failure);
+            }
+        }
+
+        let handle = self.open_handle(OpenHandle::File {
+            path: filename,
+            upload,
+            transferred: 0,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        if let Some(OpenHandle::File {
+            path,
+            upload,
+            transferred,
+        }) = self.handles.remove(&handle)
+        {
+            let action = if let Some(mut file) = upload {
+                file.flush().await.map_err(|_| StatusCode::Failure)?;
+                AuditAction::Upload
+            } else {
+                AuditAction::Download
+            };
+            self.storage.audit().record(
+                AuditEvent::for_user(&self.user, action)
+                    .with_path(path)
+                    .with_bytes(transferred),
+            );
+        }
+        Ok(ok_status(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let path = self.file_path(&handle)?;
+        let reader = self
+            .storage
+            .get(&self.user, path, offset)
+            .await
+            .map_err(status)?;
+
+        let mut data = Vec::with_capacity(len as usize);
+        reader
+            .take(len.into())
+            .read_to_end(&mut data)
+            .await
+            .map_err(|_| StatusCode::Failure)?;
+
+        if data.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        self.add_transferred(&handle, data.len() as u64);
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let Some(OpenHandle::File {
+            upload: Some(file),
+            transferred,
+            ..
+        }) = self.handles.get_mut(&handle)
+        else {
+            return Err(StatusCode::Failure);
+        };
+
+        // Only the bytes written past the end of the file take up more space.
+        let size = file
+            .metadata()
+            .await
+            .map_err(|_| StatusCode::Failure)?
+            .len();
+        let growth = (offset + data.len() as u64).saturating_sub(size);
+        let server_id = self.user.server_id();
+        if let Some(remaining) = self.quota.usage(server_id).await.remaining() {
+            if growth > remaining {
+                tracing::debug!("{} is over the disk limit", self.user);
+                return Err(StatusCode::Failure);
+            }
+        }
+
+        file.seek(SeekFrom::Start(offset))
+            .await
+            .map_err(|_| StatusCode::Failure)?;
+        file.write_all(&data)
+            .await
+            .map_err(|_| StatusCode::Failure)?;
+        self.quota.add_usage(server_id, growth).await;
+        *transferred += data.len() as u64;
+        Ok(ok_status(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let attrs = self.attributes(&path).await?;
+        Ok(Attrs { id, attrs })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let path = self.handle_path(&handle)?;
+        let attrs = self.attributes(&path).await?;
+        Ok(Attrs { id, attrs })
+    }
+
+    /// Clients set the times and modes after uploads. Changing the owner or the
+    /// size isn't supported.
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        if attrs.uid.is_some() || attrs.gid.is_some() || attrs.size.is_some() {
+            return Err(StatusCode::OpUnsupported);
+        }
+        let time = |seconds: Option<u32>| {
+            seconds.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds.into()))
+        };
+        self.storage
+            .set_attributes(
+                &self.user,
+                path,
+                attrs.permissions,
+                time(attrs.atime),
+                time(attrs.mtime),
+            )
+            .await
+            .map_err(status)?;
+        Ok(ok_status(id))
+    }
+
+    async fn fsetstat(
+        &mut self,
+        id: u32,
+        handle: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.handle_path(&handle)?;
+        russh_sftp::server::Handler::setstat(self, id, path, attrs).await
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<Handle, Self::Error> {
+        let meta = self
+            .storage
+            .metadata(&self.user, &path)
+            .await
+            .map_err(status)?;
+        if !meta.is_dir() {
+            return Err(StatusCode::NoSuchFile);
+        }
+
+        let handle = self.open_handle(OpenHandle::Dir {
+            path,
+            entries: None,
+        });
+        Ok(Handle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let unlisted = match self.handles.get(&handle) {
+            Some(OpenHandle::Dir {
+                path,
+                entries: None,
+            }) => Some(path.clone()),
+            Some(OpenHandle::Dir { .. }) => None,
+            _ => return Err(StatusCode::Failure),
+        };
+        let listed = match unlisted {
+            Some(path) => Some(self.list_dir(path).await?),
+            None => None,
+        };
+
+        let Some(OpenHandle::Dir { entries, .. }) = self.handles.get_mut(&handle) else {
+            return Err(StatusCode::Failure);
+        };
+        if let Some(listed) = listed {
+            *entries = Some(listed);
+        }
+        let entries = entries.as_mut().ok_or(StatusCode::Failure)?;
+        if entries.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+
+        let count = entries.len().min(READDIR_PAGE_SIZE);
+        let files = entries.drain(..count).collect();
+        Ok(Name { id, files })
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        self.storage
+            .del(&self.user, filename)
+            .await
+            .map_err(status)?;
+        Ok(ok_status(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        self.storage.mkd(&self.user, path).await.map_err(status)?;
+        Ok(ok_status(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        self.storage.rmd(&self.user, path).await.map_err(status)?;
+        Ok(ok_status(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        Ok(Name {
+            id,
+            files: vec![File::dummy(resolve(&path))],
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let attrs = self.attributes(&path).await?;
+        Ok(Attrs { id, attrs })
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        self.storage
+            .rename(&self.user, oldpath, newpath)
+            .await
+            .map_err(status)?;
+        Ok(ok_status(id))
+    }
+}
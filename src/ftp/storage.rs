@@ -0,0 +1,491 @@
+use std::{
+    fmt::Debug,
+    fs::FileTimes,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path, PathBuf},
+    sync::{atomic::Ordering, Arc},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use libunftp::storage::{Error, ErrorKind, Fileinfo, Result, StorageBackend};
+use tokio::fs;
+use unftp_sbe_fs::{Filesystem, Meta};
+
+use super::{
+    audit::{AuditAction, AuditEvent, AuditLog, AuditedReader},
+    auth::User,
+    permissions::Permission,
+};
+use crate::managers::quota::{path_size, LimitedReader, QuotaManager};
+
+/// Confines every session to the data directory of the server the user logged in
+/// for. Paths are resolved lexically against the server directory, so `..` can not
+/// climb above it, and symlinks which point outside of it are refused. Every
+/// operation is checked against the permissions of the user, and the ones which
+/// change or transfer files are recorded in the audit log. Uploads are cut off once
+/// the server reaches its disk limit.
+#[derive(Debug)]
+pub struct JailedFilesystem {
+    /// The directory containing the data directories of all the servers.
+    root: PathBuf,
+    inner: Filesystem,
+    audit: Arc<AuditLog>,
+    audit_transfers: bool,
+    quota: Arc<QuotaManager>,
+}
+
+impl JailedFilesystem {
+    pub fn new<P: Into<PathBuf>>(root: P, audit: Arc<AuditLog>, quota: Arc<QuotaManager>) -> Self {
+        let root = root.into();
+        Self {
+            inner: Filesystem::new(root.clone()),
+            root,
+            audit,
+            audit_transfers: true,
+            quota,
+        }
+    }
+
+    /// Stops recording every `get` and `put` as a transfer. SFTP reads and writes
+    /// files in chunks, so it records whole transfers itself.
+    pub fn without_transfer_audit(mut self) -> Self {
+        self.audit_transfers = false;
+        self
+    }
+
+    pub fn audit(&self) -> &Arc<AuditLog> {
+        &self.audit
+    }
+
+    /// Opens a file to be written at any offset, as SFTP clients do. Refused once the
+    /// server is over its disk limit, the bytes written have to be accounted for by
+    /// the caller. The space of a truncated file is given back.
+    pub async fn open_for_write<P: AsRef<Path>>(
+        &self,
+        user: &User,
+        path: P,
+        options: &fs::OpenOptions,
+    ) -> Result<fs::File> {
+        let path = self.jail(user, path, Some(Permission::Write)).await?;
+        if self.quota.usage(user.server_id()).await.remaining() == Some(0) {
+            tracing::debug!("{user} is over the disk limit");
+            return Err(ErrorKind::ExceededStorageAllocationError.into());
+        }
+
+        let real_path = self.root.join(path);
+        let old_size = file_size(&real_path).await;
+        let file = options.open(&real_path).await.map_err(local_error)?;
+        let new_size = file
+            .metadata()
+            .await
+            .map_or(old_size, |metadata| metadata.len());
+        self.quota
+            .remove_usage(user.server_id(), old_size.saturating_sub(new_size))
+            .await;
+        Ok(file)
+    }
+
+    /// Sets the mode and the times of a file. Only the permission bits can be set,
+    /// never setuid, setgid or sticky.
+    pub async fn set_attributes<P: AsRef<Path>>(
+        &self,
+        user: &User,
+        path: P,
+        mode: Option<u32>,
+        accessed: Option<SystemTime>,
+        modified: Option<SystemTime>,
+    ) -> Result<()> {
+        let path = self
+            .root
+            .join(self.jail(user, path, Some(Permission::Write)).await?);
+        tokio::task::spawn_blocking(move || -> io::Result<()> {
+            if let Some(mode) = mode {
+                std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode & 0o777))?;
+            }
+            let mut times = FileTimes::new();
+            if let Some(accessed) = accessed {
+                times = times.set_accessed(accessed);
+            }
+            if let Some(modified) = modified {
+                times = times.set_modified(modified);
+            }
+            std::fs::File::open(&path)?.set_times(times)
+        })
+        .await
+        .map_err(|e| Error::new(ErrorKind::LocalError, e))?
+        .map_err(local_error)
+    }
+
+    /// Resolves an ftp path to a path relative to the container root, which is
+    /// what the wrapped filesystem expects. Operations which only look up a path
+    /// don't need a permission.
+    async fn jail<P: AsRef<Path>>(
+        &self,
+        user: &User,
+        path: P,
+        permission: Option<Permission>,
+    ) -> Result<PathBuf> {
+        let relative = normalize(path.as_ref());
+        let jail_root = self.root.join(user.server_id());
+        let real = real_path(&jail_root, &relative).await.map_err(|e| {
+            if e.kind() == io::ErrorKind::PermissionDenied {
+                tracing::warn!("{user} tried to access {relative:?} outside of the jail");
+                ErrorKind::PermissionDenied.into()
+            } else {
+                local_error(e)
+            }
+        })?;
+
+        // Checked against where symlinks lead, so a link can't be used to reach a
+        // directory the user has no access to.
+        if let Some(permission) = permission {
+            if !user.permissions().allows(&real, permission) {
+                tracing::debug!("{user} is not allowed to {permission:?} {real:?}");
+                return Err(ErrorKind::PermissionDenied.into());
+            }
+        }
+
+        Ok(Path::new(user.server_id()).join(relative))
+    }
+}
+
+/// Resolves a path inside of the jail. Fails with [`io::ErrorKind::PermissionDenied`]
+/// if the path, or the deepest part of it which exists, resolves to somewhere
+/// outside of the jail.
+pub(crate) async fn confine(jail_root: &Path, path: &Path) -> io::Result<PathBuf> {
+    real_path(jail_root, path).await?;
+    Ok(jail_root.join(normalize(path)))
+}
+
+/// Like [`confine`], but returns where the path leads after following symlinks,
+/// relative to the jail root.
+pub(crate) async fn real_path(jail_root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let relative = normalize(path);
+    let real_root = fs::canonicalize(jail_root).await?;
+    let outside = || io::Error::new(io::ErrorKind::PermissionDenied, "Outside of the jail");
+
+    // Only the part of the path which exists can be checked, the rest is created
+    // inside the deepest existing directory.
+    let mut existing = jail_root.join(&relative);
+    let mut missing = Vec::new();
+    let real = loop {
+        match fs::canonicalize(&existing).await {
+            Ok(real) if real.starts_with(&real_root) => break real,
+            Ok(_) => return Err(outside()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                // Dangling symlinks would be followed when creating the file.
+                if fs::symlink_metadata(&existing).await.is_ok() {
+                    return Err(outside());
+                }
+                missing.extend(existing.file_name().map(ToOwned::to_owned));
+                if !existing.pop() || !existing.starts_with(jail_root) {
+                    return Err(outside());
+                }
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    let mut real = real
+        .strip_prefix(&real_root)
+        .map_err(|_| outside())?
+        .to_path_buf();
+    real.extend(missing.iter().rev());
+    Ok(real)
+}
+
+/// Lexically resolves `.` and `..`, treating the path as rooted at the jail. Like
+/// in a chroot, `..` at the root stays at the root.
+pub(crate) fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => normalized.push(part),
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            Component::RootDir | Component::CurDir | Component::Prefix(_) => {}
+        }
+    }
+    normalized
+}
+
+/// Size of the file, 0 if it doesn't exist.
+async fn file_size(path: &Path) -> u64 {
+    fs::metadata(path)
+        .await
+        .map_or(0, |metadata| metadata.len())
+}
+
+fn local_error(error: io::Error) -> Error {
+    Error::new(ErrorKind::LocalError, error)
+}
+
+#[async_trait]
+impl StorageBackend<User> for JailedFilesystem {
+    type Metadata = Meta;
+
+    fn supported_features(&self) -> u32 {
+        StorageBackend::<User>::supported_features(&self.inner)
+    }
+
+    async fn metadata<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> Result<Self::Metadata> {
+        let path = self.jail(user, path, None).await?;
+        self.inner.metadata(user, path).await
+    }
+
+    async fn md5<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<String> {
+        let path = self.jail(user, path, Some(Permission::Read)).await?;
+        self.inner.md5(user, path).await
+    }
+
+    async fn list<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+    ) -> Result<Vec<Fileinfo<PathBuf, Self::Metadata>>> {
+        let path = self.jail(user, path, Some(Permission::Read)).await?;
+        self.inner.list(user, path).await
+    }
+
+    async fn get<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        path: P,
+        start_pos: u64,
+    ) -> Result<Box<dyn tokio::io::AsyncRead + Send + Sync + Unpin>> {
+        let event = AuditEvent::for_user(user, AuditAction::Download).with_path(&path);
+        let path = self.jail(user, path, Some(Permission::Read)).await?;
+        let reader = self.inner.get(user, path, start_pos).await?;
+
+        if !self.audit_transfers {
+            return Ok(reader);
+        }
+        Ok(Box::new(AuditedReader::new(
+            reader,
+            event,
+            Arc::clone(&self.audit),
+        )))
+    }
+
+    async fn put<
+        P: AsRef<Path> + Send + Debug,
+        R: tokio::io::AsyncRead + Send + Sync + Unpin + 'static,
+    >(
+        &self,
+        user: &User,
+        input: R,
+        path: P,
+        start_pos: u64,
+    ) -> Result<u64> {
+        let event = AuditEvent::for_user(user, AuditAction::Upload).with_path(&path);
+        let path = self.jail(user, path, Some(Permission::Write)).await?;
+
+        let real_path = self.root.join(&path);
+        let old_size = file_size(&real_path).await;
+        // The part of the file which is overwritten doesn't count against the limit.
+        let limit = self
+            .quota
+            .usage(user.server_id())
+            .await
+            .remaining()
+            .map(|remaining| remaining.saturating_add(old_size.saturating_sub(start_pos)));
+        if limit == Some(0) {
+            tracing::debug!("{user} is over the disk limit");
+            return Err(ErrorKind::ExceededStorageAllocationError.into());
+        }
+
+        let input = LimitedReader::new(input, limit);
+        let exceeded = input.exceeded();
+        let result = self.inner.put(user, input, path, start_pos).await;
+
+        let new_size = file_size(&real_path).await;
+        self.quota
+            .add_usage(user.server_id(), new_size.saturating_sub(old_size))
+            .await;
+        self.quota
+            .remove_usage(user.server_id(), old_size.saturating_sub(new_size))
+            .await;
+        if exceeded.load(Ordering::Relaxed) {
+            tracing::debug!("{user} went over the disk limit while uploading");
+            return Err(ErrorKind::ExceededStorageAllocationError.into());
+        }
+        let bytes = result?;
+
+        if self.audit_transfers {
+            self.audit.record(event.with_bytes(bytes));
+        }
+        Ok(bytes)
+    }
+
+    async fn del<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let event = AuditEvent::for_user(user, AuditAction::Delete).with_path(&path);
+        let path = self.jail(user, path, Some(Permission::Delete)).await?;
+        let size = path_size(&self.root.join(&path)).await;
+        self.inner.del(user, path).await?;
+        self.quota.remove_usage(user.server_id(), size).await;
+
+        self.audit.record(event);
+        Ok(())
+    }
+
+    async fn mkd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let event = AuditEvent::for_user(user, AuditAction::CreateDir).with_path(&path);
+        let path = self.jail(user, path, Some(Permission::CreateDir)).await?;
+        self.inner.mkd(user, path).await?;
+
+        self.audit.record(event);
+        Ok(())
+    }
+
+    async fn rename<P: AsRef<Path> + Send + Debug>(
+        &self,
+        user: &User,
+        from: P,
+        to: P,
+    ) -> Result<()> {
+        let event = AuditEvent::for_user(user, AuditAction::Rename)
+            .with_path(&from)
+            .with_target(&to);
+        let from = self.jail(user, from, Some(Permission::Rename)).await?;
+        let to = self.jail(user, to, Some(Permission::Rename)).await?;
+        self.inner.rename(user, from, to).await?;
+
+        self.audit.record(event);
+        Ok(())
+    }
+
+    async fn rmd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let event = AuditEvent::for_user(user, AuditAction::RemoveDir).with_path(&path);
+        let path = self.jail(user, path, Some(Permission::Delete)).await?;
+        let size = path_size(&self.root.join(&path)).await;
+        self.inner.rmd(user, path).await?;
+        self.quota.remove_usage(user.server_id(), size).await;
+
+        self.audit.record(event);
+        Ok(())
+    }
+
+    async fn cwd<P: AsRef<Path> + Send + Debug>(&self, user: &User, path: P) -> Result<()> {
+        let path = self.jail(user, path, None).await?;
+        self.inner.cwd(user, path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    /// A jail with a `world` directory, next to a directory outside of it.
+    fn jail() -> (TempDir, PathBuf, PathBuf) {
+        let dir = TempDir::new().unwrap();
+        let jail_root = dir.path().join("jail");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(jail_root.join("world")).unwrap();
+        std::fs::create_dir(&outside).unwrap();
+        std::fs::write(outside.join("secret"), "").unwrap();
+        (dir, jail_root, outside)
+    }
+
+    fn is_outside(result: io::Result<PathBuf>) -> bool {
+        matches!(result, Err(e) if e.kind() == io::ErrorKind::PermissionDenied)
+    }
+
+    #[test]
+    fn normalize_stays_at_the_root() {
+        assert_eq!(
+            normalize(Path::new("../../etc/passwd")),
+            Path::new("etc/passwd")
+        );
+        assert_eq!(normalize(Path::new("/world/../../etc")), Path::new("etc"));
+        assert_eq!(
+            normalize(Path::new("/world/./level.dat")),
+            Path::new("world/level.dat")
+        );
+        assert_eq!(normalize(Path::new("..")), Path::new(""));
+    }
+
+    #[tokio::test]
+    async fn parent_components_stay_in_the_jail() {
+        let (_dir, jail_root, _) = jail();
+        for path in ["../outside/secret", "world/../../outside", "/../../outside"] {
+            let confined = confine(&jail_root, Path::new(path)).await.unwrap();
+            assert!(
+                confined.starts_with(&jail_root),
+                "{path} resolved to {confined:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn absolute_paths_are_rooted_at_the_jail() {
+        let (_dir, jail_root, outside) = jail();
+        let confined = confine(&jail_root, &outside.join("secret")).await.unwrap();
+        assert!(confined.starts_with(&jail_root));
+    }
+
+    #[tokio::test]
+    async fn absolute_symlinks_out_of_the_jail_are_refused() {
+        let (_dir, jail_root, outside) = jail();
+        symlink(outside.join("secret"), jail_root.join("secret")).unwrap();
+        symlink(&outside, jail_root.join("escape")).unwrap();
+
+        assert!(is_outside(confine(&jail_root, Path::new("secret")).await));
+        assert!(is_outside(confine(&jail_root, Path::new("escape")).await));
+    }
+
+    #[tokio::test]
+    async fn dangling_symlinks_are_refused() {
+        let (_dir, jail_root, outside) = jail();
+        symlink(outside.join("missing"), jail_root.join("dangling")).unwrap();
+
+        assert!(is_outside(confine(&jail_root, Path::new("dangling")).await));
+    }
+
+    #[tokio::test]
+    async fn symlinked_parents_are_resolved() {
+        let (_dir, jail_root, outside) = jail();
+        symlink(&outside, jail_root.join("escape")).unwrap();
+        symlink("world", jail_root.join("inside")).unwrap();
+
+        // The file doesn't exist yet, so only its parent can be checked.
+        assert!(is_outside(
+            confine(&jail_root, Path::new("escape/new-file")).await
+        ));
+        assert!(is_outside(
+            confine(&jail_root, Path::new("escape/secret")).await
+        ));
+        assert!(confine(&jail_root, Path::new("inside/new-file"))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn real_paths_follow_symlinks() {
+        let (_dir, jail_root, _) = jail();
+        symlink("world", jail_root.join("inside")).unwrap();
+
+        assert_eq!(
+            real_path(&jail_root, Path::new("inside/new-file"))
+                .await
+                .unwrap(),
+            Path::new("world/new-file")
+        );
+        assert_eq!(
+            real_path(&jail_root, Path::new("/../inside"))
+                .await
+                .unwrap(),
+            Path::new("world")
+        );
+    }
+}
@@ -0,0 +1,145 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::{Arc, RwLock, Weak},
+    time::{Duration, SystemTime},
+};
+
+use eyre::{bail, eyre, Result, WrapErr};
+use rustls::{
+    server::{
+        AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient, ClientHello,
+        ResolvesServerCert,
+    },
+    sign::{self, CertifiedKey},
+    Certificate, PrivateKey, RootCertStore, ServerConfig,
+};
+use tokio::fs;
+
+use crate::config::{FtpTlsSettings, FtpsClientAuth};
+
+/// Hands out the certificate which is currently loaded, so renewed certificates
+/// are used by the next handshake without rebuilding the listener.
+#[derive(Debug)]
+pub struct CertificateResolver {
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertificateResolver {
+    pub fn load(tls: &FtpTlsSettings) -> Result<Arc<Self>> {
+        Ok(Arc::new(Self {
+            key: RwLock::new(Arc::new(load_key(tls)?)),
+        }))
+    }
+
+    /// Replaces the certificate, sessions already established keep the old one.
+    pub fn reload(&self, tls: &FtpTlsSettings) -> Result<()> {
+        let key = load_key(tls)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertificateResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(Arc::clone(&self.key.read().unwrap()))
+    }
+}
+
+/// The TLS config of the FTP server, which takes its certificate from `resolver`.
+pub fn server_config(
+    tls: &FtpTlsSettings,
+    resolver: Arc<CertificateResolver>,
+) -> Result<Arc<ServerConfig>> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+    let builder = match (tls.client_auth, &tls.client_ca) {
+        (FtpsClientAuth::Off, _) => builder.with_no_client_auth(),
+        (client_auth, Some(client_ca)) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in read_certificates(client_ca)? {
+                roots.add(&certificate)?;
+            }
+            let verifier = match client_auth {
+                FtpsClientAuth::Require => AllowAnyAuthenticatedClient::new(roots).boxed(),
+                _ => AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+            };
+            builder.with_client_cert_verifier(verifier)
+        }
+        (_, None) => bail!("ftp.tls.client_ca is required to verify client certificates"),
+    };
+    Ok(Arc::new(builder.with_cert_resolver(resolver)))
+}
+
+/// Reloads the certificate of `resolver` once the certificate or the key file is
+/// modified. Stops once the resolver is dropped with the listener.
+pub async fn watch_certificates(tls: FtpTlsSettings, resolver: Weak<CertificateResolver>) {
+    let mut loaded = (
+        modified(&tls.certificate).await,
+        modified(&tls.private_key).await,
+    );
+    let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_interval));
+
+    loop {
+        interval.tick().await;
+        let Some(resolver) = resolver.upgrade() else {
+            return;
+        };
+
+        let current = (
+            modified(&tls.certificate).await,
+            modified(&tls.private_key).await,
+        );
+        // A missing file is most likely being replaced, wait for it to show up.
+        if current == loaded || current.0.is_none() || current.1.is_none() {
+            continue;
+        }
+        // Tried again on the next change, a file may have been written only partly.
+        match resolver.reload(&tls) {
+            Ok(()) => tracing::info!("Loaded the renewed FTPS certificate"),
+            Err(e) => tracing::error!("Could not load the renewed FTPS certificate: {e:#}"),
+        }
+        loaded = current;
+    }
+}
+
+fn load_key(tls: &FtpTlsSettings) -> Result<CertifiedKey> {
+    let certificates = read_certificates(&tls.certificate)?;
+    if certificates.is_empty() {
+        bail!("{} contains no certificate", tls.certificate.display());
+    }
+
+    let mut reader = BufReader::new(
+        File::open(&tls.private_key)
+            .wrap_err_with(|| format!("Could not open {}", tls.private_key.display()))?,
+    );
+    let key = std::iter::from_fn(|| rustls_pemfile::read_one(&mut reader).transpose())
+        .find_map(|item| match item {
+            Ok(
+                rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => Some(Ok(key)),
+            Ok(_) => None,
+            Err(e) => Some(Err(e)),
+        })
+        .ok_or_else(|| eyre!("{} contains no private key", tls.private_key.display()))??;
+
+    let key = sign::any_supported_type(&PrivateKey(key))
+        .map_err(|_| eyre!("{} is not a supported key", tls.private_key.display()))?;
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+fn read_certificates(path: &Path) -> Result<Vec<Certificate>> {
+    let file = File::open(path).wrap_err_with(|| format!("Could not open {}", path.display()))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .wrap_err_with(|| format!("Could not read {}", path.display()))?;
+    Ok(certificates.into_iter().map(Certificate).collect())
+}
+
+async fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .await
+        .and_then(|meta| meta.modified())
+        .ok()
+}
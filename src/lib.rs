@@ -3,6 +3,7 @@
 pub mod cli;
 pub mod config;
 pub mod ftp;
+pub mod logging;
 pub mod managers;
 pub mod panel;
 pub mod routes;
@@ -0,0 +1,110 @@
+use eyre::Result;
+use thiserror::Error;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{
+    fmt::{self, format::FmtSpan, writer::BoxMakeWriter},
+    layer::{Layered, SubscriberExt},
+    reload,
+    util::SubscriberInitExt,
+    EnvFilter, Layer, Registry,
+};
+
+use crate::config::{LogFormat, LogRotation, LoggingSettings, SpanEvent};
+
+/// The subscriber the output layer is added to.
+type Filtered = Layered<reload::Layer<EnvFilter, Registry>, Registry>;
+
+#[derive(Error, Debug)]
+pub enum LogFilterError {
+    #[error("Invalid log filter: {0}")]
+    Invalid(String),
+}
+
+/// Handle to the filter of the global subscriber, to change it at runtime.
+#[derive(Debug)]
+pub struct LogFilter {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogFilter {
+    /// The directives currently in effect.
+    pub fn get(&self) -> Result<String> {
+        Ok(self.handle.with_current(ToString::to_string)?)
+    }
+
+    /// Replaces the filter. Lasts until the daemon restarts.
+    pub fn set(&self, directives: &str) -> Result<()> {
+        let filter =
+            EnvFilter::try_new(directives).map_err(|e| LogFilterError::Invalid(e.to_string()))?;
+        self.handle.reload(filter)?;
+        tracing::info!("Changed the log filter to {directives}");
+        Ok(())
+    }
+}
+
+/// Installs the global subscriber. The guard flushes the log file when dropped, so
+/// it has to be kept until the daemon exits.
+pub fn init(settings: &LoggingSettings) -> Result<(LogFilter, Option<WorkerGuard>)> {
+    let filter = EnvFilter::try_new(&settings.filter)?;
+    let (filter, handle) = reload::Layer::new(filter);
+
+    let (writer, guard) = match &settings.file {
+        Some(file) => {
+            let rotation = match file.rotation {
+                LogRotation::Minutely => Rotation::MINUTELY,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+                LogRotation::Never => Rotation::NEVER,
+            };
+            let mut appender = RollingFileAppender::builder()
+                .rotation(rotation)
+                .filename_prefix(&file.prefix);
+            if let Some(max_files) = file.max_files {
+                appender = appender.max_log_files(max_files);
+            }
+            // Written from a separate thread, so logging doesn't block on the disk.
+            let (writer, guard) = tracing_appender::non_blocking(appender.build(&file.directory)?);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    tracing_subscriber::registry()
+        .with(filter)
+        .with(output_layer(settings, writer))
+        .try_init()?;
+
+    Ok((LogFilter { handle }, guard))
+}
+
+fn output_layer(
+    settings: &LoggingSettings,
+    writer: BoxMakeWriter,
+) -> Box<dyn Layer<Filtered> + Send + Sync> {
+    let span_events = settings
+        .span_events
+        .iter()
+        .fold(FmtSpan::NONE, |events, event| {
+            events
+                | match event {
+                    SpanEvent::New => FmtSpan::NEW,
+                    SpanEvent::Enter => FmtSpan::ENTER,
+                    SpanEvent::Exit => FmtSpan::EXIT,
+                    SpanEvent::Close => FmtSpan::CLOSE,
+                }
+        });
+    // Escape codes only make sense on a terminal.
+    let ansi = settings.file.is_none();
+
+    let layer = fmt::layer()
+        .with_writer(writer)
+        .with_span_events(span_events);
+    match settings.format {
+        LogFormat::Text => layer.with_ansi(ansi).boxed(),
+        LogFormat::Pretty => layer.pretty().with_ansi(ansi).boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    }
+}
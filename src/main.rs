@@ -2,27 +2,26 @@
 
 use std::sync::Arc;
 
+use axum::extract::FromRef;
 use clap::Parser;
 use mastiff_backend::{
-    cli, config, ftp,
+    cli, config,
+    ftp::FtpHandle,
+    logging,
     managers::{recipe::RecipeManager, Managers},
+    panel::Panel,
     routes::initialise_routes,
 };
+use tokio::signal::unix::{signal, SignalKind};
 use tracing_panic::panic_hook;
-use tracing_subscriber::fmt::format::FmtSpan;
 
 #[tokio::main]
 async fn main() {
     let cli_args = cli::Cli::parse();
     let settings = config::Settings::new(&cli_args.config_path).unwrap();
 
-    let subscriber = tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::DEBUG)
-        .with_span_events(FmtSpan::NEW | FmtSpan::CLOSE)
-        .finish();
-
-    tracing::subscriber::set_global_default(subscriber)
-        .map_err(|_err| eprintln!("Unable to set global default subscriber"));
+    // Kept until main returns, so the buffered lines are written to the log file.
+    let (log_filter, _log_guard) = logging::init(&settings.logging).unwrap();
 
     let prev_hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic_info| {
@@ -30,17 +29,29 @@ async fn main() {
         prev_hook(panic_info);
     }));
 
-    let ftp_settings = settings.ftp.clone();
-    let data_dir = settings.container_data_directory.clone();
+    let panel = Arc::new(Panel::new(settings.panel.clone(), &settings.rest_api.token));
+
+    let managers = Managers::new(&settings, panel, Arc::new(log_filter)).await;
 
-    // TODO: Move the tokio spawn inside
+    // Reload the FTP server with the settings from the config file on SIGHUP.
+    let ftp_handle = Arc::<FtpHandle>::from_ref(&managers);
+    let config_path = cli_args.config_path.clone();
     tokio::spawn(async move {
-        ftp::start_ftp_server(ftp_settings, data_dir).await;
+        let mut hangup = signal(SignalKind::hangup()).unwrap();
+        while hangup.recv().await.is_some() {
+            match config::Settings::new(&config_path) {
+                Ok(settings) => {
+                    tracing::info!("Reloading the FTP settings");
+                    if let Err(e) = ftp_handle.reload(settings.ftp).await {
+                        tracing::error!("Could not reload the FTP server: {e}");
+                    }
+                }
+                Err(e) => tracing::error!("Could not reload the config: {e}"),
+            }
+        }
     });
 
-    let managers = Managers::new(&settings).await;
-
-    let router = initialise_routes(managers);
+    let router = initialise_routes(managers, &settings.rest_api);
     let listener =
         tokio::net::TcpListener::bind((settings.rest_api.interface, settings.rest_api.port))
             .await
@@ -2,32 +2,133 @@ use std::sync::Arc;
 
 use axum::extract::FromRef;
 
-use crate::config::Settings;
+use crate::{
+    config::Settings,
+    ftp::{self, audit::AuditLog, FtpHandle},
+    logging::LogFilter,
+    panel::Panel,
+};
 
 pub mod backup;
 pub mod docker;
+pub mod files;
+pub mod firewall;
 pub mod ftp;
+pub mod metrics;
+pub mod network;
+pub mod quota;
 pub mod recipe;
+pub mod system;
 
 // TODO: Implement `ManagerFactory` which ingests the config and builds all the required managers
 #[derive(Clone, Debug)]
 pub struct Managers {
     recipe_manager: Arc<recipe::RecipeManager>,
     docker_manager: Arc<docker::DockerManager>,
+    network_manager: Arc<network::NetworkManager>,
+    quota_manager: Arc<quota::QuotaManager>,
+    file_manager: Arc<files::FileManager>,
+    system_manager: Arc<system::SystemManager>,
+    metrics_manager: Arc<metrics::MetricsManager>,
+    audit_log: Arc<AuditLog>,
+    ftp_handle: Arc<FtpHandle>,
+    log_filter: Arc<LogFilter>,
 }
 
 impl Managers {
-    pub async fn new(settings: &Settings) -> Self {
+    /// Builds the managers and starts the FTP and SFTP servers.
+    pub async fn new(settings: &Settings, panel: Arc<Panel>, log_filter: Arc<LogFilter>) -> Self {
         let docker_manager = Arc::new(docker::DockerManager::new().await);
 
         let recipe_manager = Arc::new(recipe::RecipeManager::new(
             &settings.recipe_directory,
             Arc::clone(&docker_manager),
         ));
+        Arc::clone(&recipe_manager).spawn_image_gc(settings.image_gc.clone());
+
+        let network_manager = Arc::new(network::NetworkManager::new(
+            Arc::clone(&docker_manager),
+            &settings.container_manager,
+        ));
+        if let Err(e) = network_manager.cleanup_orphaned_networks().await {
+            tracing::error!("Could not clean up orphaned networks: {e}");
+        }
+        if let Err(e) = network_manager.reconcile_egress_policies().await {
+            tracing::error!("Could not reconcile egress policies: {e}");
+        }
+        Arc::clone(&network_manager).spawn_container_watcher();
+
+        let quota_manager = Arc::new(quota::QuotaManager::new(
+            Arc::clone(&docker_manager),
+            &settings.container_data_directory,
+            settings.quota.clone(),
+        ));
+        if let Err(e) = quota_manager.load_limits().await {
+            tracing::error!("Could not load the disk limits: {e}");
+        }
+        Arc::clone(&quota_manager).spawn_scanner();
+
+        let data_dir = &settings.container_data_directory;
+        let file_manager = Arc::new(files::FileManager::new(
+            data_dir,
+            settings.file_manager.clone(),
+            &settings.rest_api.token,
+            Arc::clone(&quota_manager),
+        ));
+        if let Err(e) = file_manager.clean_uploads().await {
+            tracing::error!("Could not clean up the unfinished uploads: {e}");
+        }
+        Arc::clone(&file_manager).spawn_upload_expiry();
+
+        let audit_log = AuditLog::new(settings.ftp.audit.clone(), Arc::clone(&panel));
+        let authenticator =
+            ftp::build_authenticator(&settings.ftp, data_dir, panel, Arc::clone(&audit_log));
+
+        let metrics_manager = Arc::new(
+            metrics::MetricsManager::new(
+                Arc::clone(&docker_manager),
+                Arc::clone(&quota_manager),
+                Arc::clone(&authenticator),
+            )
+            .expect("Could not install the metrics recorder"),
+        );
+        metrics_manager.spawn_crash_watcher();
+
+        if let Some(sftp_settings) = settings.sftp.clone() {
+            tokio::spawn(ftp::sftp::supervise_sftp_server(
+                sftp_settings,
+                data_dir.clone(),
+                Arc::clone(&authenticator),
+                Arc::clone(&audit_log),
+                Arc::clone(&quota_manager),
+            ));
+        }
+
+        let ftp_handle = Arc::new(FtpHandle::spawn(
+            settings.ftp.clone(),
+            data_dir,
+            authenticator,
+            Arc::clone(&audit_log),
+            Arc::clone(&quota_manager),
+        ));
+
+        let system_manager = Arc::new(system::SystemManager::new(
+            Arc::clone(&docker_manager),
+            Arc::clone(&ftp_handle),
+            data_dir,
+        ));
 
         Self {
             recipe_manager,
             docker_manager,
+            network_manager,
+            quota_manager,
+            file_manager,
+            system_manager,
+            metrics_manager,
+            audit_log,
+            ftp_handle,
+            log_filter,
         }
     }
 }
@@ -43,3 +144,51 @@ impl FromRef<Managers> for Arc<docker::DockerManager> {
         Arc::clone(&managers.docker_manager)
     }
 }
+
+impl FromRef<Managers> for Arc<network::NetworkManager> {
+    fn from_ref(managers: &Managers) -> Arc<network::NetworkManager> {
+        Arc::clone(&managers.network_manager)
+    }
+}
+
+impl FromRef<Managers> for Arc<quota::QuotaManager> {
+    fn from_ref(managers: &Managers) -> Arc<quota::QuotaManager> {
+        Arc::clone(&managers.quota_manager)
+    }
+}
+
+impl FromRef<Managers> for Arc<files::FileManager> {
+    fn from_ref(managers: &Managers) -> Arc<files::FileManager> {
+        Arc::clone(&managers.file_manager)
+    }
+}
+
+impl FromRef<Managers> for Arc<system::SystemManager> {
+    fn from_ref(managers: &Managers) -> Arc<system::SystemManager> {
+        Arc::clone(&managers.system_manager)
+    }
+}
+
+impl FromRef<Managers> for Arc<metrics::MetricsManager> {
+    fn from_ref(managers: &Managers) -> Arc<metrics::MetricsManager> {
+        Arc::clone(&managers.metrics_manager)
+    }
+}
+
+impl FromRef<Managers> for Arc<AuditLog> {
+    fn from_ref(managers: &Managers) -> Arc<AuditLog> {
+        Arc::clone(&managers.audit_log)
+    }
+}
+
+impl FromRef<Managers> for Arc<FtpHandle> {
+    fn from_ref(managers: &Managers) -> Arc<FtpHandle> {
+        Arc::clone(&managers.ftp_handle)
+    }
+}
+
+impl FromRef<Managers> for Arc<LogFilter> {
+    fn from_ref(managers: &Managers) -> Arc<LogFilter> {
+        Arc::clone(&managers.log_filter)
+    }
+}
@@ -1,24 +1,44 @@
-use std::path::Path;
+use std::{
+    borrow::Borrow,
+    collections::{BTreeMap, HashMap, HashSet},
+    path::Path,
+};
 
 use docker_api::{
-    models::ImageBuildChunk,
-    opts::{
-        ImageBuildOpts, ImageFilter, ImageListOpts, ImagePruneOpts, ImagesPruneFilter, PullOpts,
-    },
+    models::{ImageBuildChunk, ImageSummary},
+    opts::{ContainerListOpts, ImageBuildOpts, ImageFilter, ImageListOpts, PullOpts, TagOpts},
     Docker,
 };
 use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use tokio_stream::{Stream, StreamExt};
 use tracing::instrument;
+use utoipa::ToSchema;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ImageError {
+    #[error("There is no previous image of '{0}' to rollback to")]
+    NoPreviousImage(String),
+    #[error("The previous image of '{0}' is not versioned")]
+    Unversioned(String),
+}
 
 #[derive(Debug)]
 pub struct DockerManager {
     docker: Docker,
 }
 
+/// The tag which points to the image of a recipe's active version.
+const LATEST_TAG: &str = "latest";
+
+/// Label holding [`BuildOptions::hash`] of the options a local image was built with.
+const BUILD_HASH_LABEL: &str = "mastiff.build-hash";
+
 #[derive(Debug)]
 pub struct Image {
     name: String,
+    tag: Option<String>,
     source: ImageSource,
 }
 
@@ -27,18 +47,97 @@ pub enum ImageSource {
     Local {
         /// Path to the Containerfile.
         path: String,
+        /// Name of the recipe the image is built for.
+        recipe: String,
+        /// Version of the recipe the image is built for.
+        version: String,
+        build: BuildOptions,
     },
     Registry,
 }
 
+/// Options for building a local image, set in the `[build]` section of a recipe.
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct BuildOptions {
+    /// Build arguments passed to the Dockerfile. Values provided by the panel
+    /// take precedence over these.
+    #[serde(default)]
+    pub args: HashMap<String, String>,
+    /// The stage to build in a multi-stage Dockerfile.
+    pub target: Option<String>,
+    /// Name of the Dockerfile relative to the recipe directory.
+    pub dockerfile: Option<String>,
+    /// Whether layers cached from previous builds can be reused.
+    #[serde(default)]
+    pub use_cache: bool,
+}
+
+impl BuildOptions {
+    /// Hash of the options which change the built image. The build arguments are
+    /// sorted, so their order doesn't matter.
+    fn hash(&self) -> String {
+        let args: BTreeMap<&String, &String> = self.args.iter().collect();
+        let options = serde_json::json!({
+            "args": args,
+            "target": self.target,
+            "dockerfile": self.dockerfile,
+        });
+        hex::encode(Sha256::digest(options.to_string()))
+    }
+}
+
+/// Why an image was picked up by the garbage collector.
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum GcReason {
+    /// Untagged layers left behind by builds.
+    Dangling,
+    /// The recipe which the image was built for is no longer installed.
+    RecipeRemoved,
+    /// The image belongs to a version of the recipe older than the one before the
+    /// active version, which is kept for rollbacks.
+    Outdated,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CollectedImage {
+    pub id: String,
+    pub recipe: Option<String>,
+    pub reason: GcReason,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct GcReport {
+    /// Whether the images were actually removed.
+    pub dry_run: bool,
+    pub images: Vec<CollectedImage>,
+    /// Sum of the sizes of the removed images. Layers shared with other images
+    /// are counted for each image, so the real amount may be lower.
+    pub reclaimed_bytes: u64,
+}
+
 impl Image {
-    pub fn new_local(path: impl AsRef<Path>) -> Self {
+    /// The image is tagged as `mastiff/<recipe>:<version>`, where the recipe name is
+    /// the name of the recipe directory.
+    pub fn new_local(
+        path: impl AsRef<Path>,
+        version: impl Into<String>,
+        build: BuildOptions,
+    ) -> Self {
         let path = path.as_ref();
+        // TODO: this is ugly
+        let recipe = path.file_name().unwrap().to_string_lossy().to_string();
+        let version = version.into();
+
         Image {
-            // TODO: this is ugly
-            name: path.file_name().unwrap().to_string_lossy().to_string(),
+            name: local_image_name(&recipe),
+            tag: Some(version.clone()),
             source: ImageSource::Local {
                 path: path.to_string_lossy().to_string(),
+                recipe,
+                version,
+                build,
             },
         }
     }
@@ -46,9 +145,47 @@ impl Image {
     pub fn new_registry(name: impl Into<String>) -> Self {
         Image {
             name: name.into(),
+            tag: None,
             source: ImageSource::Registry,
         }
     }
+
+    /// The full reference of the image including the tag.
+    pub fn reference(&self) -> String {
+        match &self.tag {
+            Some(tag) => format!("{}:{}", self.name, tag),
+            None => self.name.clone(),
+        }
+    }
+}
+
+/// Name of the image repository of a locally built recipe.
+fn local_image_name(recipe: &str) -> String {
+    format!("mastiff/{}", recipe.to_lowercase())
+}
+
+/// Whether the recipe name can be used as a docker repository name once
+/// lowercased: alphanumeric components separated by `.`, `_` or `-`.
+pub fn is_valid_recipe_name(name: &str) -> bool {
+    let separator = |c: char| matches!(c, '.' | '_' | '-');
+    !name.is_empty()
+        && name.len() <= 128
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || separator(c))
+        && !name.starts_with(separator)
+        && !name.ends_with(separator)
+}
+
+/// Whether the version can be used as a docker tag.
+pub fn is_valid_version(version: &str) -> bool {
+    let allowed = |c: char| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-');
+    !version.is_empty()
+        && version.len() <= 128
+        && version.chars().all(allowed)
+        && !version.starts_with(['.', '-'])
+        // Reserved for the active version.
+        && version != LATEST_TAG
 }
 
 impl DockerManager {
@@ -62,29 +199,51 @@ impl DockerManager {
         DockerManager { docker }
     }
 
+    /// The docker client, for managers which need direct access to the daemon.
+    pub fn docker(&self) -> &Docker {
+        &self.docker
+    }
+
     // Creates or Pulls the image from a dockerfile or from a registryi.
     #[instrument(skip(self), level = "debug")]
     async fn create_image(&self, image_data: Image) -> Result<()> {
         let images = self.docker.images();
+        let reference = image_data.reference();
 
         let mut image: Box<
             dyn Stream<Item = Result<ImageBuildChunk, docker_api::Error>> + Unpin + Send,
-        > = match image_data.source {
-            ImageSource::Local { path } => {
-                Box::new(
-                    // Docker only requires a directory containing the dockerfile.
-                    images.build_par(
-                        &ImageBuildOpts::builder(path)
-                            // Typo in the library
-                            .nocahe(true)
-                            .labels([("mastiff.recipe-name", image_data.name)])
-                            .build(),
-                    ),
-                )
+        > = match &image_data.source {
+            ImageSource::Local {
+                path,
+                recipe,
+                version,
+                build,
+            } => {
+                let build_hash = build.hash();
+                // Docker only requires a directory containing the dockerfile.
+                let mut opts = ImageBuildOpts::builder(path)
+                    // Typo in the library
+                    .nocahe(!build.use_cache)
+                    .tag(&reference)
+                    .build_args(build.args.clone())
+                    .labels([
+                        ("mastiff.recipe-name", recipe.as_str()),
+                        ("mastiff.recipe-version", version.as_str()),
+                        (BUILD_HASH_LABEL, build_hash.as_str()),
+                    ]);
+
+                if let Some(target) = &build.target {
+                    opts = opts.target(target);
+                }
+                if let Some(dockerfile) = &build.dockerfile {
+                    opts = opts.dockerfile(dockerfile);
+                }
+
+                Box::new(images.build_par(&opts.build()))
             }
             // TODO: Support pulling from custom registries
             ImageSource::Registry => {
-                Box::new(images.pull(&PullOpts::builder().image(image_data.name).build()))
+                Box::new(images.pull(&PullOpts::builder().image(&reference).build()))
             }
         };
 
@@ -103,6 +262,21 @@ impl DockerManager {
             }
         }
 
+        if let ImageSource::Local { .. } = image_data.source {
+            self.tag_latest(&image_data).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Makes the image the active version of its recipe. Containers created from
+    /// now on use it, the existing ones keep the image they were created with.
+    async fn tag_latest(&self, image: &Image) -> Result<()> {
+        self.docker
+            .images()
+            .get(image.reference())
+            .tag(&TagOpts::builder().repo(&image.name).tag(LATEST_TAG).build())
+            .await?;
         Ok(())
     }
 
@@ -111,14 +285,35 @@ impl DockerManager {
     pub async fn get_image(&self, img_details: Image, create: bool) -> Result<docker_api::Image> {
         let images = self.docker.images();
 
-        let image = images.get(&img_details.name);
+        let image = images.get(img_details.reference());
 
         // Check if image exists, else create it.
-        if image.inspect().await.is_err() {
-            tracing::debug!("Could not inspect image: {}", img_details.name);
+        let Ok(details) = image.inspect().await else {
+            tracing::debug!("Could not inspect image: {}", img_details.reference());
             if create {
                 self.create_image(img_details).await?;
             }
+            return Ok(image);
+        };
+
+        if let ImageSource::Local { build, .. } = &img_details.source {
+            let build_hash = details
+                .config
+                .and_then(|config| config.labels)
+                .and_then(|mut labels| labels.remove(BUILD_HASH_LABEL));
+            if create && build_hash.as_deref() != Some(build.hash().as_str()) {
+                // Built with other arguments, the old image is left to the garbage
+                // collector once it is untagged.
+                tracing::info!(
+                    "Rebuilding {} with the changed build options",
+                    img_details.reference()
+                );
+                self.create_image(img_details).await?;
+            } else {
+                // The version was built before, eg. it is uploaded again after a
+                // rollback, so it becomes the active one again.
+                self.tag_latest(&img_details).await?;
+            }
         }
         Ok(image)
     }
@@ -128,7 +323,7 @@ impl DockerManager {
     pub async fn list_images(&self) -> Result<Vec<String>> {
         let images = self.docker.images();
 
-        Ok(images
+        let mut recipes: Vec<String> = images
             .list(
                 &ImageListOpts::builder()
                     .filter([ImageFilter::LabelKey("mastiff.recipe-name".to_string())])
@@ -137,27 +332,219 @@ impl DockerManager {
             .await?
             .into_iter()
             .map(|image| image.labels.get("mastiff.recipe-name").unwrap().to_string())
-            .collect())
+            .collect();
+
+        // A recipe can have images for multiple versions.
+        recipes.sort_unstable();
+        recipes.dedup();
+        Ok(recipes)
     }
 
-    /// Deletes the image associated with a recipe.
-    #[instrument(skip(self), level = "debug")]
-    pub async fn delete_image(&self, name: &str) -> Result<()> {
-        let images = self.docker.images();
-        let pruned = images
-            .prune(
-                &ImagePruneOpts::builder()
-                    .filter([ImagesPruneFilter::Label(
+    /// Lists the images built for a recipe, newest first.
+    async fn list_recipe_images(&self, recipe: &str) -> Result<Vec<ImageSummary>> {
+        let mut images = self
+            .docker
+            .images()
+            .list(
+                &ImageListOpts::builder()
+                    .filter([ImageFilter::Label(
                         "mastiff.recipe-name".to_string(),
-                        name.to_string(),
+                        recipe.to_string(),
                     )])
                     .build(),
             )
-            .await?; // TODO: Should I use prune here?
-        tracing::debug!(
-            "Deleted {} Images associated with '{name}' recipe",
-            pruned.images_deleted.map_or(0, |imgs| imgs.len())
-        );
+            .await?;
+
+        images.sort_unstable_by(|a, b| b.created.cmp(&a.created));
+        Ok(images)
+    }
+
+    /// Deletes all the images associated with a recipe. Images which are still
+    /// used by a container are left to the garbage collector.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn delete_image(&self, name: &str) -> Result<()> {
+        let images = self.docker.images();
+        let mut deleted = 0;
+
+        for image in self.list_recipe_images(name).await? {
+            match images.get(&image.id).delete().await {
+                Ok(_) => deleted += 1,
+                Err(e) => tracing::debug!("Could not delete image {}: {}", image.id, e),
+            }
+        }
+
+        tracing::debug!("Deleted {deleted} Images associated with '{name}' recipe");
         Ok(())
     }
+
+    /// Points the `latest` tag of a recipe to the image of the version built
+    /// before the active one. Returns the version which is now active.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn rollback_image(&self, recipe: &str) -> Result<String> {
+        let name = local_image_name(recipe);
+        let recipe_images = self.list_recipe_images(recipe).await?;
+        let active = active_index(recipe, &recipe_images);
+
+        let Some(previous) = recipe_images.get(active + 1) else {
+            return Err(ImageError::NoPreviousImage(recipe.to_string()).into());
+        };
+        let Some(version) = previous.labels.get("mastiff.recipe-version") else {
+            return Err(ImageError::Unversioned(recipe.to_string()).into());
+        };
+
+        self.docker
+            .images()
+            .get(&previous.id)
+            .tag(&TagOpts::builder().repo(&name).tag(LATEST_TAG).build())
+            .await?;
+
+        tracing::info!("Rolled back '{recipe}' to version {version}");
+        Ok(version.clone())
+    }
+
+    /// Total size in bytes of the images built for recipes and the dangling build layers.
+    #[instrument(skip(self), level = "debug", ret)]
+    pub async fn image_disk_usage(&self) -> Result<u64> {
+        let (recipe_images, dangling_images) = self.list_gc_images().await?;
+
+        // Recipe images which lost their tag show up in both lists.
+        let sizes: HashMap<String, u64> = recipe_images
+            .into_iter()
+            .chain(dangling_images)
+            .map(|image| (image.id, image.size.max(0) as u64))
+            .collect();
+
+        Ok(sizes.values().sum())
+    }
+
+    /// Removes dangling images, images of recipes which are not in `installed_recipes`
+    /// and the images of inactive versions of each installed recipe, except the
+    /// previous one. Images used by any container, running or not, are never removed.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn collect_garbage(
+        &self,
+        installed_recipes: &[String],
+        dry_run: bool,
+    ) -> Result<GcReport> {
+        let (recipe_images, dangling_images) = self.list_gc_images().await?;
+
+        let used_images: HashSet<String> = self
+            .docker
+            .containers()
+            .list(&ContainerListOpts::builder().all(true).build())
+            .await?
+            .into_iter()
+            .filter_map(|container| container.image_id)
+            .collect();
+
+        let mut images_by_recipe: HashMap<&str, Vec<&ImageSummary>> = HashMap::new();
+        for image in &recipe_images {
+            let recipe = image.labels["mastiff.recipe-name"].as_str();
+            images_by_recipe.entry(recipe).or_default().push(image);
+        }
+        for images in images_by_recipe.values_mut() {
+            images.sort_unstable_by(|a, b| b.created.cmp(&a.created));
+        }
+        // The active image of every recipe and the one `rollback_image` would go
+        // back to are kept.
+        let kept_images: HashSet<&str> = images_by_recipe
+            .iter()
+            .flat_map(|(recipe, images)| {
+                let active = active_index(recipe, images);
+                images
+                    .iter()
+                    .skip(active)
+                    .take(2)
+                    .map(|image| image.id.as_str())
+            })
+            .collect();
+
+        let mut collected = Vec::new();
+        for image in &recipe_images {
+            let recipe = &image.labels["mastiff.recipe-name"];
+            let reason = if !installed_recipes.contains(recipe) {
+                GcReason::RecipeRemoved
+            } else if !kept_images.contains(image.id.as_str()) {
+                GcReason::Outdated
+            } else {
+                continue;
+            };
+
+            collected.push(CollectedImage {
+                id: image.id.clone(),
+                recipe: Some(recipe.clone()),
+                reason,
+                size: image.size.max(0) as u64,
+            });
+        }
+
+        // Recipe images which lost their tag are listed as dangling too.
+        for image in &dangling_images {
+            if collected.iter().all(|col| col.id != image.id) {
+                collected.push(CollectedImage {
+                    id: image.id.clone(),
+                    recipe: None,
+                    reason: GcReason::Dangling,
+                    size: image.size.max(0) as u64,
+                });
+            }
+        }
+
+        collected.retain(|image| !used_images.contains(&image.id));
+
+        let mut report = GcReport {
+            dry_run,
+            images: Vec::with_capacity(collected.len()),
+            reclaimed_bytes: 0,
+        };
+
+        for image in collected {
+            if !dry_run {
+                if let Err(e) = self.docker.images().get(&image.id).delete().await {
+                    tracing::warn!("Could not remove image {}: {}", image.id, e);
+                    continue;
+                }
+            }
+            tracing::debug!("Collected image {} ({:?})", image.id, image.reason);
+            report.reclaimed_bytes += image.size;
+            report.images.push(image);
+        }
+
+        Ok(report)
+    }
+
+    /// Lists the images built for recipes and the dangling images.
+    async fn list_gc_images(&self) -> Result<(Vec<ImageSummary>, Vec<ImageSummary>)> {
+        let images = self.docker.images();
+
+        let recipe_images = images
+            .list(
+                &ImageListOpts::builder()
+                    .all(true)
+                    .filter([ImageFilter::LabelKey("mastiff.recipe-name".to_string())])
+                    .build(),
+            )
+            .await?;
+
+        let dangling_images = images
+            .list(
+                &ImageListOpts::builder()
+                    .filter([ImageFilter::Dangling])
+                    .build(),
+            )
+            .await?;
+
+        Ok((recipe_images, dangling_images))
+    }
+}
+
+/// Position of the active image in the images of a recipe sorted newest first. The
+/// image with the `latest` tag is the active one, images built before versioned
+/// tags fall back to the newest one.
+fn active_index<I: Borrow<ImageSummary>>(recipe: &str, images: &[I]) -> usize {
+    let latest = format!("{}:{LATEST_TAG}", local_image_name(recipe));
+    images
+        .iter()
+        .position(|image| image.borrow().repo_tags.contains(&latest))
+        .unwrap_or(0)
 }
@@ -0,0 +1,601 @@
+pub mod archive;
+pub mod download;
+pub mod job;
+pub mod pull;
+pub mod upload;
+use std::{
+    collections::HashMap,
+    future::Future,
+    io,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, UNIX_EPOCH},
+};
+
+use eyre::{Result, WrapErr};
+use hmac::{Hmac, Mac};
+use serde::Serialize;
+use sha2::Sha256;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWriteExt},
+};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use self::{
+    archive::{ArchiveFormat, ExtractLimits},
+    job::{Job, JobNotFound, JobOperation, JobStatus},
+    upload::Upload,
+};
+use super::quota::{path_size, LimitedReader, QuotaError, QuotaManager};
+use crate::{
+    config::FileManagerSettings,
+    ftp::{
+        auth::is_valid_server_id,
+        storage::{confine, normalize},
+    },
+};
+
+/// How long finished jobs can still be looked up.
+const JOB_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum FileError {
+    #[error("Invalid server id: {0}")]
+    InvalidServerId(String),
+    #[error("{} does not exist", .0.display())]
+    NotFound(PathBuf),
+    #[error("{} already exists", .0.display())]
+    AlreadyExists(PathBuf),
+    /// The path resolves to somewhere outside of the server's data directory.
+    #[error("{} is outside of the server directory", .0.display())]
+    OutsideJail(PathBuf),
+    #[error("{}: {reason}", .path.display())]
+    InvalidPath { path: PathBuf, reason: &'static str },
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct FileEntry {
+    pub name: String,
+    pub size: u64,
+    /// Unix timestamp in seconds.
+    pub modified: u64,
+    /// Permission bits in octal, eg. `644`.
+    pub mode: String,
+    pub is_dir: bool,
+    pub is_symlink: bool,
+    /// Guessed from the extension, `None` for directories.
+    pub mime: Option<String>,
+}
+
+/// Manages the files in the data directories of the servers for the file manager
+/// of the panel. Paths are confined to the server's data directory with the same
+/// rules as the FTP jail, and writes are refused once the server is over its disk
+/// limit. Archives are compressed and extracted by jobs running in the background.
+///
+/// Large files are uploaded in chunks which can be resumed, and downloaded with
+/// signed links so they don't have to pass through the panel.
+#[derive(Debug)]
+pub struct FileManager {
+    data_directory: PathBuf,
+    settings: FileManagerSettings,
+    quota: Arc<QuotaManager>,
+    jobs: Arc<Mutex<HashMap<String, Job>>>,
+    uploads: Mutex<HashMap<String, Arc<Upload>>>,
+    /// Key the download links are signed with, see [`download_key`].
+    download_key: Vec<u8>,
+    /// Ids of the download links which were used, until they expire. Only kept in
+    /// memory, so links which were used before a restart work once more until they
+    /// expire, which `download_link_max_age` bounds.
+    used_downloads: Mutex<HashMap<String, u64>>,
+}
+
+impl FileManager {
+    pub fn new(
+        data_directory: impl Into<PathBuf>,
+        settings: FileManagerSettings,
+        node_token: &str,
+        quota: Arc<QuotaManager>,
+    ) -> Self {
+        Self {
+            data_directory: data_directory.into(),
+            settings,
+            quota,
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            uploads: Mutex::new(HashMap::new()),
+            download_key: download_key(node_token),
+            used_downloads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lists a directory, directories first and then by name.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn list(&self, server_id: &str, path: &str) -> Result<Vec<FileEntry>> {
+        let directory = self.resolve(server_id, path).await?;
+        let mut read_dir = fs::read_dir(&directory)
+            .await
+            .map_err(|e| io_error(path, e))?;
+
+        let mut entries = Vec::new();
+        while let Some(entry) = read_dir.next_entry().await? {
+            let metadata = entry.metadata().await?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let mime = (!metadata.is_dir())
+                .then(|| mime_guess::from_path(&name).first().map(|m| m.to_string()))
+                .flatten();
+
+            entries.push(FileEntry {
+                size: metadata.len(),
+                modified: metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .map_or(0, |time| time.as_secs()),
+                mode: format!("{:o}", metadata.permissions().mode() & 0o7777),
+                is_dir: metadata.is_dir(),
+                is_symlink: metadata.is_symlink(),
+                mime,
+                name,
+            });
+        }
+
+        entries.sort_by(|a, b| b.is_dir.cmp(&a.is_dir).then_with(|| a.name.cmp(&b.name)));
+        Ok(entries)
+    }
+
+    /// Opens a file for reading, returns it with its size.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn read_file(&self, server_id: &str, path: &str) -> Result<(fs::File, u64)> {
+        let real_path = self.resolve(server_id, path).await?;
+        let file = fs::File::open(&real_path)
+            .await
+            .map_err(|e| io_error(path, e))?;
+        let metadata = file.metadata().await?;
+        if metadata.is_dir() {
+            return Err(invalid_path(path, "is a directory"));
+        }
+        Ok((file, metadata.len()))
+    }
+
+    /// Writes a file, replacing it if it exists. Missing parent directories are
+    /// created. Returns the no of bytes written.
+    ///
+    /// The file is written next to the target and renamed over it once complete, so
+    /// a failed write leaves the old file as it was.
+    #[instrument(skip(self, reader), level = "debug")]
+    pub async fn write_file(
+        &self,
+        server_id: &str,
+        path: &str,
+        reader: impl AsyncRead + Unpin + Send,
+    ) -> Result<u64> {
+        let real_path = self.resolve_new(server_id, path).await?;
+        self.quota.ensure_within_limit(server_id).await?;
+
+        // The replaced file doesn't count against the limit.
+        let old_size = fs::metadata(&real_path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        let limit = self
+            .quota
+            .usage(server_id)
+            .await
+            .remaining()
+            .map(|remaining| remaining.saturating_add(old_size));
+        let mut reader = LimitedReader::new(reader, limit);
+        let exceeded = reader.exceeded();
+
+        let temp_path = normalize(Path::new(path))
+            .with_file_name(format!(".mastiff-{}.tmp", uuid::Uuid::new_v4().simple()));
+        let (real_temp_path, mut file) = self
+            .create_new(server_id, &temp_path.to_string_lossy())
+            .await?;
+
+        let result: Result<u64> = async {
+            let result = tokio::io::copy(&mut reader, &mut file).await;
+            if exceeded.load(Ordering::Relaxed) {
+                return Err(QuotaError::Exceeded(server_id.to_string()).into());
+            }
+            let bytes = result.wrap_err("Could not write the file")?;
+            file.flush().await?;
+            drop(file);
+
+            // Checked again, the parents could have been replaced while writing.
+            let real_path = self.resolve_new(server_id, path).await?;
+            fs::rename(&real_temp_path, &real_path)
+                .await
+                .map_err(|e| io_error(path, e))?;
+            Ok(bytes)
+        }
+        .await;
+
+        match result {
+            Ok(bytes) => {
+                self.quota.remove_usage(server_id, old_size).await;
+                self.quota.add_usage(server_id, bytes).await;
+                Ok(bytes)
+            }
+            Err(e) => {
+                let _ = fs::remove_file(&real_temp_path).await;
+                Err(e)
+            }
+        }
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn create_dir(&self, server_id: &str, path: &str) -> Result<()> {
+        let real_path = self.resolve_new(server_id, path).await?;
+        fs::create_dir_all(&real_path)
+            .await
+            .map_err(|e| io_error(path, e))
+    }
+
+    /// Renames or moves a file or a directory. Fails if the target exists.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn rename(&self, server_id: &str, from: &str, to: &str) -> Result<()> {
+        let real_from = self.resolve_new(server_id, from).await?;
+        let real_to = self.resolve_new(server_id, to).await?;
+        ensure_absent(&real_to, to).await?;
+        if real_to.starts_with(&real_from) {
+            return Err(invalid_path(to, "can't be moved into itself"));
+        }
+
+        fs::rename(&real_from, &real_to)
+            .await
+            .map_err(|e| io_error(from, e))
+    }
+
+    /// Copies a file or a directory with everything in it. Symlinks are skipped.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn copy(&self, server_id: &str, from: &str, to: &str) -> Result<()> {
+        let real_from = self.resolve_new(server_id, from).await?;
+        let real_to = self.resolve_new(server_id, to).await?;
+        ensure_absent(&real_to, to).await?;
+        if real_to.starts_with(&real_from) {
+            return Err(invalid_path(to, "can't be copied into itself"));
+        }
+        self.quota.ensure_within_limit(server_id).await?;
+
+        let mut remaining = self.quota.usage(server_id).await.remaining();
+        let target = real_to.clone();
+        let result = tokio::task::spawn_blocking(move || {
+            copy_recursive(&real_from, &target, &mut remaining)
+        })
+        .await?;
+
+        match result {
+            Ok(bytes) => {
+                self.quota.add_usage(server_id, bytes).await;
+                Ok(())
+            }
+            Err(e) if e.kind() == io::ErrorKind::StorageFull => {
+                // Nothing is left of a copy which doesn't fit.
+                let _ = match fs::symlink_metadata(&real_to).await {
+                    Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&real_to).await,
+                    _ => fs::remove_file(&real_to).await,
+                };
+                Err(QuotaError::Exceeded(server_id.to_string()).into())
+            }
+            Err(e) => Err(io_error(from, e)),
+        }
+    }
+
+    /// Deletes files and directories with everything in them. All the paths are
+    /// checked before anything is deleted.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn delete(&self, server_id: &str, paths: &[String]) -> Result<()> {
+        let mut real_paths = Vec::with_capacity(paths.len());
+        for path in paths {
+            real_paths.push((path, self.resolve_new(server_id, path).await?));
+        }
+
+        for (path, real_path) in real_paths {
+            let metadata = fs::symlink_metadata(&real_path)
+                .await
+                .map_err(|e| io_error(path, e))?;
+            let size = path_size(&real_path).await;
+            let result = if metadata.is_dir() {
+                fs::remove_dir_all(&real_path).await
+            } else {
+                fs::remove_file(&real_path).await
+            };
+            result.map_err(|e| io_error(path, e))?;
+            self.quota.remove_usage(server_id, size).await;
+        }
+        Ok(())
+    }
+
+    /// Sets the permission bits of a file or a directory. The setuid, setgid and
+    /// sticky bits are ignored.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn chmod(&self, server_id: &str, path: &str, mode: u32) -> Result<()> {
+        let real_path = self.resolve(server_id, path).await?;
+        fs::set_permissions(&real_path, std::fs::Permissions::from_mode(mode & 0o777))
+            .await
+            .map_err(|e| io_error(path, e))
+    }
+
+    /// Starts compressing the paths into a new archive at `destination`. The format is
+    /// taken from its extension.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn compress(
+        &self,
+        server_id: &str,
+        paths: &[String],
+        destination: &str,
+    ) -> Result<JobStatus> {
+        let format = ArchiveFormat::from_path(destination)?;
+        let mut sources = Vec::with_capacity(paths.len());
+        for path in paths {
+            let source = self.resolve_new(server_id, path).await?;
+            fs::symlink_metadata(&source)
+                .await
+                .map_err(|e| io_error(path, e))?;
+            sources.push(source);
+        }
+        self.resolve_new(server_id, destination).await?;
+        self.quota.ensure_within_limit(server_id).await?;
+
+        let entries =
+            tokio::task::spawn_blocking(move || archive::collect_sources(&sources)).await??;
+        // Created after the sources are listed, so it isn't added to itself.
+        let (real_destination, file) = self.create_new(server_id, destination).await?;
+        let max_size = self.quota.usage(server_id).await.remaining();
+        let job = Job::new(
+            server_id,
+            JobOperation::Compress,
+            archive::total_size(&entries),
+        );
+        let progress = Arc::clone(&job.processed);
+        let quota = Arc::clone(&self.quota);
+        let server_id = server_id.to_string();
+
+        Ok(self.spawn_job(job, async move {
+            let result = archive::compress(format, entries, file, max_size, progress).await;
+            if let Err(e) = result {
+                let _ = fs::remove_file(&real_destination).await;
+                let storage_full = e
+                    .downcast_ref::<io::Error>()
+                    .is_some_and(|e| e.kind() == io::ErrorKind::StorageFull);
+                return Err(if storage_full {
+                    QuotaError::Exceeded(server_id).into()
+                } else {
+                    e
+                });
+            }
+
+            let bytes = fs::metadata(&real_destination).await?.len();
+            quota.add_usage(&server_id, bytes).await;
+            Ok(bytes)
+        }))
+    }
+
+    /// Starts extracting the archive into `destination`, or next to the archive if
+    /// not given. Existing files are overwritten.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn decompress(
+        &self,
+        server_id: &str,
+        path: &str,
+        destination: Option<&str>,
+    ) -> Result<JobStatus> {
+        let format = ArchiveFormat::from_path(path)?;
+        let real_archive = self.resolve(server_id, path).await?;
+        let size = fs::metadata(&real_archive)
+            .await
+            .map_err(|e| io_error(path, e))?
+            .len();
+
+        let destination = match destination {
+            Some(destination) => destination.to_string(),
+            None => normalize(Path::new(path))
+                .parent()
+                .map(|parent| parent.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        let real_destination = self.resolve(server_id, &destination).await?;
+
+        self.quota.ensure_within_limit(server_id).await?;
+        let remaining = self.quota.usage(server_id).await.remaining();
+        let limits = ExtractLimits {
+            max_size: remaining.map_or(self.settings.max_extracted_size, |remaining| {
+                remaining.min(self.settings.max_extracted_size)
+            }),
+            max_entries: self.settings.max_archive_entries,
+        };
+
+        let job = Job::new(server_id, JobOperation::Decompress, size);
+        let progress = Arc::clone(&job.processed);
+        let quota = Arc::clone(&self.quota);
+        let server_id = server_id.to_string();
+
+        Ok(self.spawn_job(job, async move {
+            let written = Arc::new(AtomicU64::new(0));
+            let result = archive::extract(
+                format,
+                real_archive,
+                real_destination,
+                limits,
+                progress,
+                Arc::clone(&written),
+            )
+            .await;
+            // The files extracted before a failure are kept, so they count as well.
+            quota
+                .add_usage(&server_id, written.load(Ordering::Relaxed))
+                .await;
+            result
+        }))
+    }
+
+    /// The jobs of the server, including the ones which finished recently.
+    pub fn jobs(&self, server_id: &str) -> Vec<JobStatus> {
+        let mut jobs: Vec<_> = self
+            .jobs
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|job| job.status.server_id == server_id)
+            .map(Job::status)
+            .collect();
+        jobs.sort_by_key(|job| job.started);
+        jobs
+    }
+
+    pub fn job(&self, server_id: &str, job_id: &str) -> Result<JobStatus> {
+        self.jobs
+            .lock()
+            .unwrap()
+            .get(job_id)
+            .filter(|job| job.status.server_id == server_id)
+            .map(Job::status)
+            .ok_or_else(|| JobNotFound(job_id.to_string()).into())
+    }
+
+    fn spawn_job<F>(&self, job: Job, task: F) -> JobStatus
+    where
+        F: Future<Output = Result<u64>> + Send + 'static,
+    {
+        let status = job.status();
+        let id = status.id.clone();
+
+        let now = job::now();
+        let mut jobs = self.jobs.lock().unwrap();
+        jobs.retain(|_, existing| {
+            !existing
+                .status
+                .finished
+                .is_some_and(|finished| now.saturating_sub(finished) >= JOB_RETENTION.as_secs())
+        });
+        jobs.insert(id.clone(), job);
+        drop(jobs);
+
+        let jobs = Arc::clone(&self.jobs);
+        tokio::spawn(async move {
+            let result = task.await;
+            if let Err(e) = &result {
+                tracing::error!("Job {id} failed: {e:#}");
+            }
+            if let Some(job) = jobs.lock().unwrap().get_mut(&id) {
+                job.finish(&result);
+            }
+        });
+        status
+    }
+
+    /// Resolves a path inside the server's data directory.
+    async fn resolve(&self, server_id: &str, path: &str) -> Result<PathBuf> {
+        if !is_valid_server_id(server_id) {
+            return Err(FileError::InvalidServerId(server_id.to_string()).into());
+        }
+
+        let jail_root = self.data_directory.join(server_id);
+        confine(&jail_root, Path::new(path))
+            .await
+            .map_err(|e| match e.kind() {
+                io::ErrorKind::PermissionDenied => {
+                    FileError::OutsideJail(display_path(path)).into()
+                }
+                _ => io_error(path, e),
+            })
+    }
+
+    /// Like [`Self::resolve`], but refuses the root of the data directory, for the
+    /// operations which create or remove the path itself.
+    async fn resolve_new(&self, server_id: &str, path: &str) -> Result<PathBuf> {
+        if normalize(Path::new(path)).as_os_str().is_empty() {
+            return Err(invalid_path(path, "is the server directory"));
+        }
+        self.resolve(server_id, path).await
+    }
+
+    /// Creates the file and its missing parents when the request arrives, so the
+    /// server can't put a symlink in its place while a job runs. Fails if anything,
+    /// including a symlink, is at the path already.
+    async fn create_new(&self, server_id: &str, path: &str) -> Result<(PathBuf, fs::File)> {
+        let real_path = self.resolve_new(server_id, path).await?;
+        if let Some(parent) = real_path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .map_err(|e| io_error(path, e))?;
+        }
+        // Checked again, the parents could have been replaced in the meantime.
+        let real_path = self.resolve_new(server_id, path).await?;
+
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .open(&real_path)
+            .await
+            .map_err(|e| io_error(path, e))?;
+        Ok((real_path, file))
+    }
+}
+
+/// Derives the key of the download links from the node token, so neither can be
+/// used in place of the other.
+fn download_key(node_token: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(node_token.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(b"mastiff download-link");
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// The path as shown to the user, relative to the server directory.
+fn display_path(path: &str) -> PathBuf {
+    Path::new("/").join(normalize(Path::new(path)))
+}
+
+fn invalid_path(path: &str, reason: &'static str) -> eyre::Error {
+    FileError::InvalidPath {
+        path: display_path(path),
+        reason,
+    }
+    .into()
+}
+
+/// Maps the errors users can cause to [`FileError`]s.
+fn io_error(path: &str, error: io::Error) -> eyre::Error {
+    match error.kind() {
+        io::ErrorKind::NotFound => FileError::NotFound(display_path(path)).into(),
+        io::ErrorKind::AlreadyExists => FileError::AlreadyExists(display_path(path)).into(),
+        _ => eyre::Error::new(error).wrap_err(display_path(path).display().to_string()),
+    }
+}
+
+async fn ensure_absent(real_path: &Path, path: &str) -> Result<()> {
+    if fs::symlink_metadata(real_path).await.is_ok() {
+        return Err(FileError::AlreadyExists(display_path(path)).into());
+    }
+    Ok(())
+}
+
+/// Copies `from` to `to`, returning the no of bytes copied. Symlinks are not
+/// followed, as they could point outside of the jail.
+fn copy_recursive(from: &Path, to: &Path, remaining: &mut Option<u64>) -> io::Result<u64> {
+    let metadata = std::fs::symlink_metadata(from)?;
+    if metadata.is_dir() {
+        std::fs::create_dir(to)?;
+        let mut bytes = 0;
+        for entry in std::fs::read_dir(from)? {
+            let entry = entry?;
+            bytes += copy_recursive(&entry.path(), &to.join(entry.file_name()), remaining)?;
+        }
+        Ok(bytes)
+    } else if metadata.is_file() {
+        if let Some(remaining) = remaining {
+            *remaining = remaining
+                .checked_sub(metadata.len())
+                .ok_or_else(|| io::Error::from(io::ErrorKind::StorageFull))?;
+        }
+        std::fs::copy(from, to)
+    } else {
+        Ok(0)
+    }
+}
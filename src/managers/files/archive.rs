@@ -0,0 +1,374 @@
+use std::{
+    io::{self, Read},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+use async_compression::tokio::{
+    bufread::{GzipDecoder, ZstdDecoder},
+    write::{GzipEncoder, ZstdEncoder},
+};
+use eyre::Result;
+use serde::Serialize;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader, ReadBuf},
+};
+use tokio_stream::StreamExt;
+use tokio_tar::{Archive, Builder};
+use zip::{write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::managers::quota::LimitedWriter;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("Unsupported archive format: {0}")]
+    UnsupportedFormat(String),
+    #[error("The archive extracts to more than {0} bytes")]
+    TooLarge(u64),
+    #[error("The archive has more than {0} entries")]
+    TooManyEntries(usize),
+    /// The entry would be extracted outside of the destination.
+    #[error("Unsafe path in the archive: {}", .0.display())]
+    UnsafePath(PathBuf),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Detects the format from the extension of the file name.
+    pub fn from_path(path: &str) -> Result<Self> {
+        let name = path.to_lowercase();
+        let format = if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            ArchiveFormat::TarGz
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            ArchiveFormat::TarZst
+        } else if name.ends_with(".tar") {
+            ArchiveFormat::Tar
+        } else if name.ends_with(".zip") {
+            ArchiveFormat::Zip
+        } else {
+            return Err(ArchiveError::UnsupportedFormat(path.to_string()).into());
+        };
+        Ok(format)
+    }
+}
+
+/// Limits which protect against archives extracting to far more than their size.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct ExtractLimits {
+    pub max_size: u64,
+    pub max_entries: usize,
+}
+
+/// A file or a directory to be added to an archive.
+#[derive(Debug)]
+pub(super) struct SourceEntry {
+    path: PathBuf,
+    /// Path inside of the archive.
+    name: PathBuf,
+    size: u64,
+    is_dir: bool,
+}
+
+/// Lists the files and directories below the sources, which are added to the root
+/// of the archive under their own name. Symlinks are skipped, as they could point
+/// outside of the jail.
+pub(super) fn collect_sources(sources: &[PathBuf]) -> io::Result<Vec<SourceEntry>> {
+    fn walk(path: &Path, name: PathBuf, entries: &mut Vec<SourceEntry>) -> io::Result<()> {
+        let metadata = std::fs::symlink_metadata(path)?;
+        if metadata.is_dir() {
+            entries.push(SourceEntry {
+                path: path.to_path_buf(),
+                name: name.clone(),
+                size: 0,
+                is_dir: true,
+            });
+            for entry in std::fs::read_dir(path)? {
+                let entry = entry?;
+                walk(&entry.path(), name.join(entry.file_name()), entries)?;
+            }
+        } else if metadata.is_file() {
+            entries.push(SourceEntry {
+                path: path.to_path_buf(),
+                name,
+                size: metadata.len(),
+                is_dir: false,
+            });
+        }
+        Ok(())
+    }
+
+    let mut entries = Vec::new();
+    for source in sources {
+        let name = source.file_name().map(PathBuf::from).unwrap_or_default();
+        walk(source, name, &mut entries)?;
+    }
+    Ok(entries)
+}
+
+pub(super) fn total_size(entries: &[SourceEntry]) -> u64 {
+    entries.iter().map(|entry| entry.size).sum()
+}
+
+/// Writes the entries to the archive, an empty file created beforehand. Fails with
+/// [`io::ErrorKind::StorageFull`] if the archive would get larger than `max_size`.
+pub(super) async fn compress(
+    format: ArchiveFormat,
+    entries: Vec<SourceEntry>,
+    file: fs::File,
+    max_size: Option<u64>,
+    progress: Arc<AtomicU64>,
+) -> Result<()> {
+    if format == ArchiveFormat::Zip {
+        let file = LimitedWriter::new(file.into_std().await, max_size);
+        let exceeded = file.exceeded();
+        let result =
+            tokio::task::spawn_blocking(move || compress_zip(&entries, file, &progress)).await?;
+        return result.map_err(|e| storage_full_if(&exceeded, e));
+    }
+
+    let file = LimitedWriter::new(file, max_size);
+    let exceeded = file.exceeded();
+    write_tar(format, entries, file, progress)
+        .await
+        .map_err(|e| storage_full_if(&exceeded, e))
+}
+
+/// The error of a write which hit the limit is wrapped differently by every
+/// encoder, so it is replaced.
+fn storage_full_if(exceeded: &AtomicBool, error: eyre::Error) -> eyre::Error {
+    if exceeded.load(Ordering::Relaxed) {
+        io::Error::from(io::ErrorKind::StorageFull).into()
+    } else {
+        error
+    }
+}
+
+async fn write_tar(
+    format: ArchiveFormat,
+    entries: Vec<SourceEntry>,
+    file: LimitedWriter<fs::File>,
+    progress: Arc<AtomicU64>,
+) -> Result<()> {
+    let writer: Box<dyn AsyncWrite + Unpin + Send> = match format {
+        ArchiveFormat::TarGz => Box::new(GzipEncoder::new(file)),
+        ArchiveFormat::TarZst => Box::new(ZstdEncoder::new(file)),
+        _ => Box::new(file),
+    };
+
+    let mut builder = Builder::new(writer);
+    builder.follow_symlinks(false);
+    for entry in &entries {
+        if entry.is_dir {
+            builder.append_dir(&entry.name, &entry.path).await?;
+        } else {
+            builder
+                .append_path_with_name(&entry.path, &entry.name)
+                .await?;
+        }
+        progress.fetch_add(entry.size, Ordering::Relaxed);
+    }
+
+    // Finishes the compressed stream as well.
+    builder.into_inner().await?.shutdown().await?;
+    Ok(())
+}
+
+fn compress_zip(
+    entries: &[SourceEntry],
+    file: LimitedWriter<std::fs::File>,
+    progress: &AtomicU64,
+) -> Result<()> {
+    let mut zip = ZipWriter::new(file);
+    let options = SimpleFileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true);
+
+    for entry in entries {
+        let name = entry.name.to_string_lossy();
+        let mode = std::fs::metadata(&entry.path)?.permissions().mode();
+        let options = options.unix_permissions(mode & 0o777);
+
+        if entry.is_dir {
+            zip.add_directory(name, options)?;
+        } else {
+            zip.start_file(name, options)?;
+            io::copy(&mut std::fs::File::open(&entry.path)?, &mut zip)?;
+        }
+        progress.fetch_add(entry.size, Ordering::Relaxed);
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+/// Extracts the archive into `destination`, returning the no of bytes extracted.
+/// Only files and directories are extracted, links are skipped. The files written
+/// are kept if it fails, `written` counts their bytes either way.
+pub(super) async fn extract(
+    format: ArchiveFormat,
+    archive: PathBuf,
+    destination: PathBuf,
+    limits: ExtractLimits,
+    progress: Arc<AtomicU64>,
+    written: Arc<AtomicU64>,
+) -> Result<u64> {
+    if format == ArchiveFormat::Zip {
+        return tokio::task::spawn_blocking(move || {
+            extract_zip(&archive, &destination, limits, &progress, &written)
+        })
+        .await?;
+    }
+
+    let reader = BufReader::new(CountingReader {
+        inner: fs::File::open(&archive).await?,
+        progress,
+    });
+    let decoder: Box<dyn AsyncRead + Unpin + Send> = match format {
+        ArchiveFormat::TarGz => Box::new(GzipDecoder::new(reader)),
+        ArchiveFormat::TarZst => Box::new(ZstdDecoder::new(reader)),
+        _ => Box::new(reader),
+    };
+
+    fs::create_dir_all(&destination).await?;
+    let mut archive = Archive::new(decoder);
+    let mut entries = archive.entries()?;
+    let (mut count, mut size) = (0, 0);
+
+    while let Some(entry) = entries.next().await {
+        let mut entry = entry?;
+        count += 1;
+        if count > limits.max_entries {
+            return Err(ArchiveError::TooManyEntries(limits.max_entries).into());
+        }
+
+        let kind = entry.header().entry_type();
+        if !kind.is_file() && !kind.is_dir() {
+            tracing::debug!("Skipping {kind:?} entry {:?}", entry.path()?);
+            continue;
+        }
+
+        // The reader stops at the size in the header, so it can be trusted.
+        size += entry.header().size()?;
+        if size > limits.max_size {
+            return Err(ArchiveError::TooLarge(limits.max_size).into());
+        }
+
+        if !entry.unpack_in(&destination).await? {
+            return Err(ArchiveError::UnsafePath(entry.path()?.into_owned()).into());
+        }
+        written.fetch_add(entry.header().size()?, Ordering::Relaxed);
+    }
+    Ok(size)
+}
+
+fn extract_zip(
+    archive: &Path,
+    destination: &Path,
+    limits: ExtractLimits,
+    progress: &AtomicU64,
+    written: &AtomicU64,
+) -> Result<u64> {
+    let mut zip = ZipArchive::new(std::fs::File::open(archive)?)?;
+    if zip.len() > limits.max_entries {
+        return Err(ArchiveError::TooManyEntries(limits.max_entries).into());
+    }
+
+    std::fs::create_dir_all(destination)?;
+    let real_destination = std::fs::canonicalize(destination)?;
+    let mut size = 0;
+
+    for index in 0..zip.len() {
+        let mut file = zip.by_index(index)?;
+        let Some(target) = file.enclosed_name().map(|name| destination.join(name)) else {
+            return Err(ArchiveError::UnsafePath(file.name().into()).into());
+        };
+
+        if file.is_dir() {
+            if !create_dir_inside(&target, &real_destination)? {
+                return Err(ArchiveError::UnsafePath(file.name().into()).into());
+            }
+        } else if file.is_file() {
+            let parent = target.parent().unwrap_or(destination);
+            if !create_dir_inside(parent, &real_destination)? {
+                return Err(ArchiveError::UnsafePath(file.name().into()).into());
+            }
+            if std::fs::symlink_metadata(&target).is_ok_and(|meta| meta.is_symlink()) {
+                std::fs::remove_file(&target)?;
+            }
+
+            // The sizes in the archive can't be trusted, so the bytes written are
+            // counted instead.
+            let remaining = limits.max_size - size;
+            let mut output = std::fs::File::create(&target)?;
+            let copied = io::copy(&mut (&mut file).take(remaining + 1), &mut output)?;
+            written.fetch_add(copied, Ordering::Relaxed);
+            size += copied;
+            if size > limits.max_size {
+                return Err(ArchiveError::TooLarge(limits.max_size).into());
+            }
+
+            if let Some(mode) = file.unix_mode() {
+                std::fs::set_permissions(&target, std::fs::Permissions::from_mode(mode & 0o777))?;
+            }
+        } else {
+            tracing::debug!("Skipping zip entry {}", file.name());
+        }
+
+        progress.fetch_add(file.compressed_size(), Ordering::Relaxed);
+    }
+    Ok(size)
+}
+
+/// Creates the directory and its missing parents, unless it or the deepest part of
+/// it which exists resolves to outside of `real_destination`, as directories which
+/// already exist could be symlinks. Returns whether it is inside.
+fn create_dir_inside(dir: &Path, real_destination: &Path) -> io::Result<bool> {
+    let mut existing = dir;
+    while std::fs::symlink_metadata(existing).is_err() {
+        match existing.parent() {
+            Some(parent) => existing = parent,
+            None => return Ok(false),
+        }
+    }
+    if !std::fs::canonicalize(existing)?.starts_with(real_destination) {
+        return Ok(false);
+    }
+
+    std::fs::create_dir_all(dir)?;
+    Ok(std::fs::canonicalize(dir)?.starts_with(real_destination))
+}
+
+/// Counts the bytes read from the archive, for the progress of the job.
+struct CountingReader<R> {
+    inner: R,
+    progress: Arc<AtomicU64>,
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for CountingReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.progress
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        poll
+    }
+}
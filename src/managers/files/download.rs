@@ -0,0 +1,94 @@
+use eyre::Result;
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header, Validation};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tracing::instrument;
+
+use super::{invalid_path, io_error, job::now, FileManager};
+
+#[derive(Debug, thiserror::Error)]
+pub enum DownloadError {
+    #[error("The download link is invalid or has expired")]
+    InvalidToken,
+    #[error("The download link has already been used")]
+    AlreadyUsed,
+}
+
+/// Claims of the tokens in the download links, signed with a key derived from the
+/// node token.
+#[derive(Debug, Serialize, Deserialize)]
+struct DownloadClaims {
+    server_id: String,
+    path: String,
+    exp: u64,
+    /// Unique id, so a link can only be used once.
+    jti: String,
+}
+
+impl FileManager {
+    /// Signs a link to download the file without other credentials. The link can be
+    /// used once, within `expires_in` seconds, capped by the configured maximum.
+    /// Returns the token and when it expires.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn sign_download(
+        &self,
+        server_id: &str,
+        path: &str,
+        expires_in: Option<u64>,
+    ) -> Result<(String, u64)> {
+        let real_path = self.resolve(server_id, path).await?;
+        let metadata = fs::metadata(&real_path)
+            .await
+            .map_err(|e| io_error(path, e))?;
+        if metadata.is_dir() {
+            return Err(invalid_path(path, "is a directory"));
+        }
+
+        let max_age = self.settings.download_link_max_age;
+        let exp = now() + expires_in.map_or(max_age, |expires_in| expires_in.min(max_age));
+        let claims = DownloadClaims {
+            server_id: server_id.to_string(),
+            path: path.to_string(),
+            exp,
+            jti: uuid::Uuid::new_v4().to_string(),
+        };
+        let token = jsonwebtoken::encode(
+            &Header::new(Algorithm::HS256),
+            &claims,
+            &EncodingKey::from_secret(&self.download_key),
+        )?;
+        Ok((token, exp))
+    }
+
+    /// Checks the token of a download link and opens the file it points to. Returns
+    /// the file with its size and name.
+    #[instrument(skip(self, token), level = "debug")]
+    pub async fn redeem_download(&self, token: &str) -> Result<(fs::File, u64, String)> {
+        // Without leeway, so the used ids can be forgotten once they expire.
+        let mut validation = Validation::new(Algorithm::HS256);
+        validation.leeway = 0;
+        let claims = jsonwebtoken::decode::<DownloadClaims>(
+            token,
+            &DecodingKey::from_secret(&self.download_key),
+            &validation,
+        )
+        .map_err(|_| DownloadError::InvalidToken)?
+        .claims;
+
+        {
+            let now = now();
+            let mut used = self.used_downloads.lock().unwrap();
+            used.retain(|_, exp| *exp >= now);
+            if used.insert(claims.jti.clone(), claims.exp).is_some() {
+                return Err(DownloadError::AlreadyUsed.into());
+            }
+        }
+
+        let (file, size) = self.read_file(&claims.server_id, &claims.path).await?;
+        let name = std::path::Path::new(&claims.path).file_name().map_or_else(
+            || "download".to_string(),
+            |name| name.to_string_lossy().to_string(),
+        );
+        Ok((file, size, name))
+    }
+}
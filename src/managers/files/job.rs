@@ -0,0 +1,100 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use eyre::Result;
+use serde::Serialize;
+use utoipa::ToSchema;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Job {0} not found")]
+pub struct JobNotFound(pub String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum JobOperation {
+    Compress,
+    Decompress,
+    Pull,
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum JobState {
+    Running,
+    Completed,
+    Failed { error: String },
+}
+
+/// An operation running in the background, as reported to the API.
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct JobStatus {
+    pub id: String,
+    pub server_id: String,
+    pub operation: JobOperation,
+    #[serde(flatten)]
+    pub state: JobState,
+    /// Bytes processed so far, of the files being compressed, of the archive being
+    /// extracted or of the file being pulled.
+    pub processed: u64,
+    pub total: u64,
+    /// Unix timestamp in seconds.
+    pub started: u64,
+    pub finished: Option<u64>,
+}
+
+#[derive(Debug)]
+pub(super) struct Job {
+    pub status: JobStatus,
+    pub processed: Arc<AtomicU64>,
+    /// Only known once a pull has received the headers.
+    pub total: Arc<AtomicU64>,
+}
+
+impl Job {
+    pub fn new(server_id: &str, operation: JobOperation, total: u64) -> Self {
+        Self {
+            status: JobStatus {
+                id: uuid::Uuid::new_v4().to_string(),
+                server_id: server_id.to_string(),
+                operation,
+                state: JobState::Running,
+                processed: 0,
+                total,
+                started: now(),
+                finished: None,
+            },
+            processed: Arc::new(AtomicU64::new(0)),
+            total: Arc::new(AtomicU64::new(total)),
+        }
+    }
+
+    pub fn status(&self) -> JobStatus {
+        JobStatus {
+            processed: self.processed.load(Ordering::Relaxed),
+            total: self.total.load(Ordering::Relaxed),
+            ..self.status.clone()
+        }
+    }
+
+    pub fn finish(&mut self, result: &Result<u64>) {
+        self.status.finished = Some(now());
+        self.status.state = match result {
+            Ok(_) => JobState::Completed,
+            Err(e) => JobState::Failed {
+                error: format!("{e:#}"),
+            },
+        };
+    }
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
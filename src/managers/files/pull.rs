@@ -0,0 +1,303 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use eyre::Result;
+use reqwest::{header, redirect, Client};
+use tokio::{fs, io::AsyncWriteExt};
+use tracing::instrument;
+use url::{Host, Url};
+
+use super::{
+    job::{Job, JobOperation, JobStatus},
+    FileManager,
+};
+
+/// Redirects followed before giving up.
+const MAX_REDIRECTS: usize = 5;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PullError {
+    #[error("Invalid url: {0}")]
+    InvalidUrl(String),
+    /// The host resolves to a loopback, private or otherwise internal address.
+    #[error("Pulling from {0} is not allowed")]
+    ForbiddenAddress(IpAddr),
+    #[error("The file is larger than {0} bytes")]
+    TooLarge(u64),
+    #[error("Too many redirects")]
+    TooManyRedirects,
+    #[error("The server responded with {0}")]
+    Status(u16),
+}
+
+impl FileManager {
+    /// Starts downloading the file at `url` to `path`. Only public HTTP(S) addresses
+    /// can be pulled from, so the node can't be used to reach internal services.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn pull(&self, server_id: &str, url: &str, path: &str) -> Result<JobStatus> {
+        let url = Url::parse(url).map_err(|e| PullError::InvalidUrl(e.to_string()))?;
+        resolve_public(&url).await?;
+
+        self.quota.ensure_within_limit(server_id).await?;
+        let (real_path, file) = self.create_new(server_id, path).await?;
+
+        let remaining = self.quota.usage(server_id).await.remaining();
+        let max_size = remaining.map_or(self.settings.max_pull_size, |remaining| {
+            remaining.min(self.settings.max_pull_size)
+        });
+        let timeout = Duration::from_secs(self.settings.pull_timeout);
+
+        let job = Job::new(server_id, JobOperation::Pull, 0);
+        let progress = Arc::clone(&job.processed);
+        let total = Arc::clone(&job.total);
+        let quota = Arc::clone(&self.quota);
+        let server_id = server_id.to_string();
+
+        Ok(self.spawn_job(job, async move {
+            let result = download(url, file, max_size, timeout, progress, total).await;
+            if result.is_err() {
+                let _ = fs::remove_file(&real_path).await;
+            }
+
+            let bytes = result?;
+            quota.add_usage(&server_id, bytes).await;
+            Ok(bytes)
+        }))
+    }
+}
+
+/// Downloads the file, following redirects as long as they stay on public addresses.
+async fn download(
+    mut url: Url,
+    mut file: fs::File,
+    max_size: u64,
+    timeout: Duration,
+    progress: Arc<AtomicU64>,
+    total: Arc<AtomicU64>,
+) -> Result<u64> {
+    let mut redirects = 0;
+    let mut response = loop {
+        // The checked address is the one connected to, so the host can't resolve
+        // to a different one in between.
+        let address = resolve_public(&url).await?;
+        // A proxy would connect to the host itself, bypassing the check above.
+        let mut client = Client::builder()
+            .no_proxy()
+            .redirect(redirect::Policy::none())
+            .timeout(timeout)
+            .user_agent("mastiff");
+        if let Some(Host::Domain(domain)) = url.host() {
+            client = client.resolve(domain, address);
+        }
+
+        let response = client.build()?.get(url.clone()).send().await?;
+        if !response.status().is_redirection() {
+            break response;
+        }
+
+        redirects += 1;
+        if redirects > MAX_REDIRECTS {
+            return Err(PullError::TooManyRedirects.into());
+        }
+        let location = response
+            .headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .ok_or_else(|| PullError::InvalidUrl("Redirect without a location".to_string()))?;
+        url = url
+            .join(location)
+            .map_err(|e| PullError::InvalidUrl(e.to_string()))?;
+    };
+
+    if !response.status().is_success() {
+        return Err(PullError::Status(response.status().as_u16()).into());
+    }
+    if let Some(length) = response.content_length() {
+        if length > max_size {
+            return Err(PullError::TooLarge(max_size).into());
+        }
+        total.store(length, Ordering::Relaxed);
+    }
+
+    let mut written = 0;
+    // The length in the headers can't be trusted, so the bytes are counted too.
+    while let Some(chunk) = response.chunk().await? {
+        written += chunk.len() as u64;
+        if written > max_size {
+            return Err(PullError::TooLarge(max_size).into());
+        }
+        file.write_all(&chunk).await?;
+        progress.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+    }
+    file.flush().await?;
+    Ok(written)
+}
+
+/// Resolves the host of the url, failing unless it is HTTP(S) and every address it
+/// resolves to is public.
+async fn resolve_public(url: &Url) -> Result<SocketAddr> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(PullError::InvalidUrl(format!("Unsupported scheme {}", url.scheme())).into());
+    }
+    let port = url
+        .port_or_known_default()
+        .ok_or_else(|| PullError::InvalidUrl(url.to_string()))?;
+
+    let addresses: Vec<SocketAddr> = match url.host() {
+        Some(Host::Domain(domain)) => tokio::net::lookup_host((domain, port)).await?.collect(),
+        Some(Host::Ipv4(ip)) => vec![SocketAddr::new(IpAddr::V4(ip), port)],
+        Some(Host::Ipv6(ip)) => vec![SocketAddr::new(IpAddr::V6(ip), port)],
+        None => return Err(PullError::InvalidUrl(url.to_string()).into()),
+    };
+
+    if let Some(address) = addresses.iter().find(|address| !is_public(address.ip())) {
+        return Err(PullError::ForbiddenAddress(address.ip()).into());
+    }
+    addresses
+        .first()
+        .copied()
+        .ok_or_else(|| PullError::InvalidUrl(format!("{url} does not resolve")).into())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match embedded_v4(ip) {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+/// The IPv4 address an IPv6 address leads to, for the IPv4-mapped (`::ffff:0:0/96`),
+/// IPv4-compatible (`::/96`), NAT64 (`64:ff9b::/96`) and 6to4 (`2002::/16`) ranges.
+fn embedded_v4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    let v4 = |high: u16, low: u16| Ipv4Addr::from((u32::from(high) << 16) | u32::from(low));
+
+    if let Some(ip) = ip.to_ipv4() {
+        return Some(ip);
+    }
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] => Some(v4(high, low)),
+        [0x2002, high, low, ..] => Some(v4(high, low)),
+        _ => None,
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [first, second, third, _] = ip.octets();
+    // 100.64.0.0/10 is used for carrier-grade NAT.
+    let shared = first == 100 && (second & 0b1100_0000) == 64;
+    // 198.18.0.0/15 is used for benchmarking.
+    let benchmarking = first == 198 && (second & 0b1111_1110) == 18;
+    // 192.0.0.0/24 is reserved for protocol assignments.
+    let protocol_assignments = first == 192 && second == 0 && third == 0;
+    // 240.0.0.0/4 is reserved, including the broadcast address.
+    let reserved = first >= 240;
+
+    !(ip.is_private()
+        || ip.is_loopback()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || shared
+        || benchmarking
+        || protocol_assignments
+        || reserved
+        || first == 0)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let [first, second, ..] = ip.segments();
+    // fc00::/7 are unique local and fe80::/10 link local addresses.
+    let unique_local = (first & 0xfe00) == 0xfc00;
+    let link_local = (first & 0xffc0) == 0xfe80;
+    // 64:ff9b:1::/48 is NAT64 for local networks.
+    let local_nat64 = first == 0x64 && second == 0xff9b;
+
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        || unique_local
+        || link_local
+        || local_nat64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn public(ip: &str) -> bool {
+        is_public(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn public_addresses_are_allowed() {
+        for ip in ["1.1.1.1", "93.184.216.34", "2606:4700:4700::1111"] {
+            assert!(public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn internal_ipv4_addresses_are_refused() {
+        for ip in [
+            "0.0.0.0",
+            "10.0.0.1",
+            "100.64.0.1",
+            "127.0.0.1",
+            "169.254.169.254",
+            "172.16.0.1",
+            "192.0.0.8",
+            "192.0.2.1",
+            "192.168.1.1",
+            "198.18.0.1",
+            "198.19.255.255",
+            "224.0.0.1",
+            "240.0.0.1",
+            "255.255.255.255",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn internal_ipv6_addresses_are_refused() {
+        for ip in [
+            "::",
+            "::1",
+            "fc00::1",
+            "fd12::1",
+            "fe80::1",
+            "ff02::1",
+            "64:ff9b:1::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+    }
+
+    #[test]
+    fn embedded_ipv4_addresses_are_checked() {
+        for ip in [
+            "::ffff:127.0.0.1",
+            "::127.0.0.1",
+            "::10.0.0.1",
+            "64:ff9b::7f00:1",
+            "64:ff9b::a9fe:a9fe",
+            "2002:7f00:1::",
+            "2002:c0a8:101::1",
+        ] {
+            assert!(!public(ip), "{ip}");
+        }
+        for ip in ["::ffff:1.1.1.1", "64:ff9b::101:101", "2002:101:101::1"] {
+            assert!(public(ip), "{ip}");
+        }
+    }
+}
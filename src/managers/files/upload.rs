@@ -0,0 +1,301 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use eyre::{Result, WrapErr};
+use serde::Serialize;
+use tokio::{
+    fs::{self, OpenOptions},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    sync::Mutex,
+};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::{display_path, job::now, FileManager};
+use crate::managers::quota::QuotaError;
+
+/// How often the expired uploads are discarded.
+const UPLOAD_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    #[error("Upload {0} not found")]
+    NotFound(String),
+    /// Another chunk of the upload is being written.
+    #[error("Upload {0} is busy")]
+    Busy(String),
+    #[error("The upload is at offset {expected}, not {actual}")]
+    OffsetMismatch { expected: u64, actual: u64 },
+    #[error("The chunk goes past the size of the upload, {size} bytes")]
+    TooLarge { size: u64 },
+    #[error("Only {offset} of {size} bytes have been uploaded")]
+    Incomplete { offset: u64, size: u64 },
+    #[error("Uploads can't be larger than {0} bytes")]
+    SizeLimit(u64),
+}
+
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct UploadStatus {
+    pub id: String,
+    #[schema(value_type = String)]
+    pub path: PathBuf,
+    /// Bytes received so far, the next chunk has to start here.
+    pub offset: u64,
+    pub size: u64,
+    /// Unix timestamp in seconds after which the upload is discarded, unless more
+    /// chunks arrive.
+    pub expires: u64,
+}
+
+#[derive(Debug)]
+pub(super) struct Upload {
+    server_id: String,
+    path: String,
+    /// The file the chunks are written to until the upload is finished.
+    staging: PathBuf,
+    size: u64,
+    /// Locked while a chunk is written.
+    progress: Mutex<Progress>,
+}
+
+#[derive(Debug)]
+struct Progress {
+    offset: u64,
+    last_activity: u64,
+}
+
+impl FileManager {
+    /// Starts an upload of `size` bytes to `path`. The chunks are written to the
+    /// upload directory and only moved into the server directory once complete.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn create_upload(
+        &self,
+        server_id: &str,
+        path: &str,
+        size: u64,
+    ) -> Result<UploadStatus> {
+        if size > self.settings.max_upload_size {
+            return Err(UploadError::SizeLimit(self.settings.max_upload_size).into());
+        }
+        self.resolve_new(server_id, path).await?;
+        let remaining = self.quota.usage(server_id).await.remaining();
+        self.remove_expired_uploads();
+
+        let id = uuid::Uuid::new_v4().to_string();
+        let staging = self.settings.upload_directory.join(format!("{id}.part"));
+        let upload = Arc::new(Upload {
+            server_id: server_id.to_string(),
+            path: path.to_string(),
+            staging,
+            size,
+            progress: Mutex::new(Progress {
+                offset: 0,
+                last_activity: now(),
+            }),
+        });
+
+        {
+            // The unfinished uploads of the server keep their size reserved, so
+            // concurrent ones can't go over the limit together.
+            let mut uploads = self.uploads.lock().unwrap();
+            let reserved: u64 = uploads
+                .values()
+                .filter(|upload| upload.server_id == server_id)
+                .map(|upload| upload.size)
+                .sum();
+            if remaining.is_some_and(|remaining| remaining < reserved.saturating_add(size)) {
+                return Err(QuotaError::Exceeded(server_id.to_string()).into());
+            }
+            uploads.insert(id.clone(), Arc::clone(&upload));
+        }
+
+        let created = async {
+            fs::create_dir_all(&self.settings.upload_directory).await?;
+            fs::File::create(&upload.staging).await
+        };
+        if let Err(e) = created.await {
+            self.uploads.lock().unwrap().remove(&id);
+            return Err(e.into());
+        }
+        Ok(self.upload_status_of(id, &upload, 0, now()))
+    }
+
+    pub async fn upload_status(&self, server_id: &str, id: &str) -> Result<UploadStatus> {
+        let upload = self.find_upload(server_id, id)?;
+        let progress = upload.progress.lock().await;
+        Ok(self.upload_status_of(
+            id.to_string(),
+            &upload,
+            progress.offset,
+            progress.last_activity,
+        ))
+    }
+
+    /// Appends a chunk starting at `offset`. If the chunk is cut short, the bytes
+    /// which arrived are kept, so the client can resume from the new offset.
+    #[instrument(skip(self, reader), level = "debug")]
+    pub async fn append_upload(
+        &self,
+        server_id: &str,
+        id: &str,
+        offset: u64,
+        reader: impl AsyncRead + Unpin + Send,
+    ) -> Result<UploadStatus> {
+        let upload = self.find_upload(server_id, id)?;
+        let Ok(mut progress) = upload.progress.try_lock() else {
+            return Err(UploadError::Busy(id.to_string()).into());
+        };
+        if offset != progress.offset {
+            return Err(UploadError::OffsetMismatch {
+                expected: progress.offset,
+                actual: offset,
+            }
+            .into());
+        }
+
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(&upload.staging)
+            .await?;
+        // One byte more than allowed is read, to tell if the chunk is too large.
+        let remaining = upload.size - progress.offset;
+        let mut reader = reader.take(remaining.saturating_add(1));
+        let copied = tokio::io::copy(&mut reader, &mut file).await;
+        file.flush().await?;
+
+        let written = file.metadata().await?.len();
+        if written > upload.size {
+            file.set_len(progress.offset).await?;
+            return Err(UploadError::TooLarge { size: upload.size }.into());
+        }
+        progress.offset = written;
+        progress.last_activity = now();
+        copied.wrap_err("The chunk was cut short")?;
+
+        Ok(self.upload_status_of(id.to_string(), &upload, written, progress.last_activity))
+    }
+
+    /// Moves the complete upload to its path, replacing the file there.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn finish_upload(&self, server_id: &str, id: &str) -> Result<()> {
+        let upload = self.find_upload(server_id, id)?;
+        let Ok(progress) = upload.progress.try_lock() else {
+            return Err(UploadError::Busy(id.to_string()).into());
+        };
+        if progress.offset != upload.size {
+            return Err(UploadError::Incomplete {
+                offset: progress.offset,
+                size: upload.size,
+            }
+            .into());
+        }
+
+        // Resolved again, the directories could have changed since the upload began.
+        let real_path = self.resolve_new(server_id, &upload.path).await?;
+        if let Some(parent) = real_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let old_size = fs::metadata(&real_path)
+            .await
+            .map_or(0, |metadata| metadata.len());
+        move_file(&upload.staging, &real_path).await?;
+        self.quota.add_usage(server_id, upload.size).await;
+        self.quota.remove_usage(server_id, old_size).await;
+
+        self.uploads.lock().unwrap().remove(id);
+        Ok(())
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn cancel_upload(&self, server_id: &str, id: &str) -> Result<()> {
+        self.find_upload(server_id, id)?;
+        if let Some(upload) = self.uploads.lock().unwrap().remove(id) {
+            remove_staging(&upload);
+        }
+        Ok(())
+    }
+
+    /// Removes the uploads left over from before a restart, which can't be resumed.
+    pub async fn clean_uploads(&self) -> Result<()> {
+        match fs::remove_dir_all(&self.settings.upload_directory).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Spawns the task discarding the uploads which expired, so their chunks don't
+    /// take up space until the next upload is created.
+    pub fn spawn_upload_expiry(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(UPLOAD_EXPIRY_INTERVAL);
+            loop {
+                interval.tick().await;
+                self.remove_expired_uploads();
+            }
+        });
+    }
+
+    fn remove_expired_uploads(&self) {
+        let expired_before = now().saturating_sub(self.settings.upload_expiry);
+        self.uploads.lock().unwrap().retain(|id, upload| {
+            // Uploads receiving a chunk are busy, so certainly not expired.
+            let expired = upload
+                .progress
+                .try_lock()
+                .is_ok_and(|progress| progress.last_activity < expired_before);
+            if expired {
+                tracing::debug!("Upload {id} expired");
+                remove_staging(upload);
+            }
+            !expired
+        });
+    }
+
+    fn find_upload(&self, server_id: &str, id: &str) -> Result<Arc<Upload>> {
+        self.uploads
+            .lock()
+            .unwrap()
+            .get(id)
+            .filter(|upload| upload.server_id == server_id)
+            .cloned()
+            .ok_or_else(|| UploadError::NotFound(id.to_string()).into())
+    }
+
+    fn upload_status_of(
+        &self,
+        id: String,
+        upload: &Upload,
+        offset: u64,
+        last_activity: u64,
+    ) -> UploadStatus {
+        UploadStatus {
+            id,
+            path: display_path(&upload.path),
+            offset,
+            size: upload.size,
+            expires: last_activity + self.settings.upload_expiry,
+        }
+    }
+}
+
+/// Renames the file, or copies it if the upload directory is on another filesystem.
+async fn move_file(from: &Path, to: &Path) -> Result<()> {
+    if fs::rename(from, to).await.is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to).await?;
+    fs::remove_file(from).await?;
+    Ok(())
+}
+
+fn remove_staging(upload: &Upload) {
+    let staging = upload.staging.clone();
+    tokio::spawn(async move {
+        if let Err(e) = fs::remove_file(&staging).await {
+            tracing::warn!("Could not remove {}: {e}", staging.display());
+        }
+    });
+}
@@ -0,0 +1,341 @@
+use std::{collections::HashSet, net::IpAddr};
+
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::process::Command;
+use tracing::instrument;
+use utoipa::ToSchema;
+
+/// Comment attached to every rule created by mastiff, so they can be told apart
+/// from the rules created by docker or the administrator.
+const RULE_COMMENT: &str = "mastiff";
+
+/// Prefix of the chains holding the egress rules of a server.
+const EGRESS_CHAIN_PREFIX: &str = "MST-EG-";
+
+/// Suffixes of the two chains of a server. The new rules are built in the chain
+/// which isn't in use, so the old rules apply until the jump is swapped.
+const EGRESS_CHAIN_SLOTS: [&str; 2] = ["-A", "-B"];
+
+/// Docker leaves this chain for user defined rules on forwarded traffic.
+const DOCKER_USER_CHAIN: &str = "DOCKER-USER";
+
+/// Which outbound connections a server is allowed to open.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum EgressPolicy {
+    AllowAll,
+    DenyAll,
+    /// Only the connections matching any of the rules are allowed.
+    Allowlist {
+        rules: Vec<EgressRule>,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol {
+    Tcp,
+    Udp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct EgressRule {
+    /// The destination network, eg. `1.1.1.1/32`.
+    pub cidr: String,
+    /// If left `None`, both TCP and UDP are allowed.
+    pub protocol: Option<Protocol>,
+    /// Destination ports. If left `None`, all the ports are allowed.
+    pub ports: Option<PortRange>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, ToSchema)]
+pub struct PortRange {
+    pub start: u16,
+    /// Inclusive, so a single port is `{ "start": 53, "end": 53 }`.
+    pub end: u16,
+}
+
+impl EgressPolicy {
+    /// Checks that the rules can be turned into valid iptables rules.
+    pub fn validate(&self) -> Result<()> {
+        let EgressPolicy::Allowlist { rules } = self else {
+            return Ok(());
+        };
+
+        for rule in rules {
+            let (addr, prefix) = rule.cidr.split_once('/').unwrap_or((&rule.cidr, "32"));
+            let addr: IpAddr = addr
+                .parse()
+                .map_err(|_| eyre!("Invalid address in '{}'", rule.cidr))?;
+            let prefix: u8 = prefix
+                .parse()
+                .map_err(|_| eyre!("Invalid prefix in '{}'", rule.cidr))?;
+
+            // The rules are only applied with `iptables`, which is IPv4 only.
+            if !addr.is_ipv4() || prefix > 32 {
+                bail!("'{}' is not a valid IPv4 network", rule.cidr);
+            }
+            if let Some(ports) = &rule.ports {
+                if ports.start > ports.end {
+                    bail!("Port range of '{}' is empty", rule.cidr);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs `iptables` with the given arguments.
+async fn iptables(args: &[&str]) -> Result<bool> {
+    let output = Command::new("iptables").args(args).output().await?;
+
+    // Exit code 1 is returned when checking for a rule or listing a chain which
+    // does not exist.
+    match output.status.code() {
+        Some(0) => Ok(true),
+        Some(1) if matches!(args.first(), Some(&"-C" | &"-L")) => Ok(false),
+        _ => bail!(
+            "iptables {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ),
+    }
+}
+
+/// Inserts the rule at the top of the chain, unless it already exists.
+async fn insert_rule(chain: &str, rule: &[&str]) -> Result<()> {
+    let check = [&["-C", chain][..], rule].concat();
+    if !iptables(&check).await? {
+        iptables(&[&["-I", chain][..], rule].concat()).await?;
+    }
+    Ok(())
+}
+
+/// Deletes the rule from the chain, if it exists.
+async fn delete_rule(chain: &str, rule: &[&str]) -> Result<()> {
+    let check = [&["-C", chain][..], rule].concat();
+    if iptables(&check).await? {
+        iptables(&[&["-D", chain][..], rule].concat()).await?;
+    }
+    Ok(())
+}
+
+/// Drops new connections from the containers on the bridge to the host. Replies to
+/// connections opened by the host are still allowed.
+#[instrument(level = "debug")]
+pub async fn block_host_access(bridge: &str) -> Result<()> {
+    insert_rule("INPUT", &host_access_rule(bridge)).await
+}
+
+/// Removes the rule added by [`block_host_access`].
+#[instrument(level = "debug")]
+pub async fn unblock_host_access(bridge: &str) -> Result<()> {
+    delete_rule("INPUT", &host_access_rule(bridge)).await
+}
+
+fn host_access_rule(bridge: &str) -> Vec<&str> {
+    vec![
+        "-i",
+        bridge,
+        "-m",
+        "conntrack",
+        "--ctstate",
+        "NEW",
+        "-m",
+        "comment",
+        "--comment",
+        RULE_COMMENT,
+        "-j",
+        "DROP",
+    ]
+}
+
+/// Replaces the egress rules of a server with the ones generated from the policy.
+/// `sources` are the bridges the server's container is attached to, each with the
+/// address of the container on it.
+///
+/// The rules are built in a new chain and jumped to before the old chain is
+/// removed, so the server is never left without rules.
+#[instrument(level = "debug")]
+pub async fn apply_egress_policy(
+    server_id: &str,
+    sources: &[(String, String)],
+    policy: &EgressPolicy,
+) -> Result<()> {
+    let [first, second] = egress_chains(server_id);
+    let (chain, old_chain) = if jump_rules(&first).await?.is_empty() {
+        (first, second)
+    } else {
+        (second, first)
+    };
+
+    // Left over from a swap which was interrupted.
+    remove_chain(&chain).await?;
+    iptables(&["-N", &chain]).await?;
+
+    // Replies and traffic within the server's network are left to docker.
+    append_rule(
+        &chain,
+        &[
+            "-m",
+            "conntrack",
+            "--ctstate",
+            "ESTABLISHED,RELATED",
+            "-j",
+            "RETURN",
+        ],
+    )
+    .await?;
+    for (bridge, _) in sources {
+        append_rule(&chain, &["-i", bridge, "-o", bridge, "-j", "RETURN"]).await?;
+    }
+
+    match policy {
+        EgressPolicy::AllowAll => append_rule(&chain, &["-j", "RETURN"]).await?,
+        EgressPolicy::DenyAll => append_rule(&chain, &["-j", "DROP"]).await?,
+        EgressPolicy::Allowlist { rules } => {
+            for rule in rules {
+                for args in allowlist_rule(rule) {
+                    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+                    append_rule(&chain, &args).await?;
+                }
+            }
+            append_rule(&chain, &["-j", "DROP"]).await?;
+        }
+    }
+
+    for (bridge, address) in sources {
+        let jump = [
+            "-i",
+            bridge,
+            "-s",
+            address,
+            "-m",
+            "comment",
+            "--comment",
+            RULE_COMMENT,
+            "-j",
+            chain.as_str(),
+        ];
+        insert_rule(DOCKER_USER_CHAIN, &jump).await?;
+    }
+    remove_chain(&old_chain).await
+}
+
+/// Removes the egress rules of a server, if there are any.
+#[instrument(level = "debug")]
+pub async fn remove_egress_policy(server_id: &str) -> Result<()> {
+    for chain in egress_chains(server_id) {
+        remove_chain(&chain).await?;
+    }
+    Ok(())
+}
+
+/// Removes the egress rules of all the servers not in `server_ids`, including the
+/// chains named by older versions.
+#[instrument(skip(server_ids), level = "debug")]
+pub async fn retain_egress_policies<'a>(
+    server_ids: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    let kept: HashSet<String> = server_ids.into_iter().flat_map(egress_chains).collect();
+
+    let output = Command::new("iptables").arg("-S").output().await?;
+    let chains: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|rule| rule.strip_prefix("-N "))
+        .filter(|chain| chain.starts_with(EGRESS_CHAIN_PREFIX) && !kept.contains(*chain))
+        .map(str::to_string)
+        .collect();
+
+    for chain in chains {
+        tracing::info!("Removing stale egress chain {chain}");
+        remove_chain(&chain).await?;
+    }
+    Ok(())
+}
+
+async fn chain_exists(chain: &str) -> Result<bool> {
+    iptables(&["-L", chain, "-n"]).await
+}
+
+/// Removes the chain and the rules jumping to it, if it exists.
+async fn remove_chain(chain: &str) -> Result<()> {
+    if !chain_exists(chain).await? {
+        return Ok(());
+    }
+
+    // The jump rule is deleted as listed, as the matches it was created with may not
+    // be known anymore.
+    for rule in jump_rules(chain).await? {
+        let args: Vec<&str> = rule.iter().map(String::as_str).collect();
+        iptables(&[&["-D"][..], &args].concat()).await?;
+    }
+
+    iptables(&["-F", chain]).await?;
+    iptables(&["-X", chain]).await?;
+    Ok(())
+}
+
+/// The rules of `DOCKER-USER` jumping to the chain, as listed without the `-A`.
+async fn jump_rules(chain: &str) -> Result<Vec<Vec<String>>> {
+    let output = Command::new("iptables")
+        .args(["-S", DOCKER_USER_CHAIN])
+        .output()
+        .await?;
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|rule| rule.strip_prefix("-A "))
+        .map(|rule| rule.split_whitespace().map(str::to_string).collect())
+        .filter(|args: &Vec<String>| args.ends_with(&["-j".to_string(), chain.to_string()]))
+        .collect())
+}
+
+async fn append_rule(chain: &str, rule: &[&str]) -> Result<()> {
+    iptables(&[&["-A", chain][..], rule].concat()).await?;
+    Ok(())
+}
+
+/// The two chains of a server. iptables limits chain names to 28 characters, so
+/// the server id is hashed.
+fn egress_chains(server_id: &str) -> [String; 2] {
+    let hash = hex::encode(Sha256::digest(server_id));
+    let hash = &hash[..28 - EGRESS_CHAIN_PREFIX.len() - 2];
+    EGRESS_CHAIN_SLOTS.map(|slot| format!("{EGRESS_CHAIN_PREFIX}{hash}{slot}"))
+}
+
+fn allowlist_rule(rule: &EgressRule) -> Vec<Vec<String>> {
+    let protocols = match (&rule.protocol, &rule.ports) {
+        (Some(protocol), _) => vec![Some(protocol)],
+        // Ports can only be matched together with a protocol.
+        (None, Some(_)) => vec![Some(&Protocol::Tcp), Some(&Protocol::Udp)],
+        (None, None) => vec![None],
+    };
+
+    protocols
+        .into_iter()
+        .map(|protocol| {
+            let mut args = vec!["-d".to_string(), rule.cidr.clone()];
+            if let Some(protocol) = protocol {
+                args.extend(["-p".to_string(), protocol_name(protocol).to_string()]);
+            }
+            if let Some(ports) = &rule.ports {
+                args.extend([
+                    "--dport".to_string(),
+                    format!("{}:{}", ports.start, ports.end),
+                ]);
+            }
+            args.extend(["-j".to_string(), "RETURN".to_string()]);
+            args
+        })
+        .collect()
+}
+
+fn protocol_name(protocol: &Protocol) -> &'static str {
+    match protocol {
+        Protocol::Tcp => "tcp",
+        Protocol::Udp => "udp",
+    }
+}
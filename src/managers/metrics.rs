@@ -0,0 +1,382 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use docker_api::{
+    opts::{ContainerListOpts, EventsOpts},
+    Docker,
+};
+use eyre::{eyre, Result};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use metrics_util::MetricKindMask;
+use serde_json::Value;
+use tokio::task::JoinSet;
+use tokio_stream::StreamExt;
+use tracing::instrument;
+
+use super::{docker::DockerManager, quota::QuotaManager};
+use crate::ftp::auth::AuthManager;
+
+const SERVER_LABEL: &str = "mastiff.server-id";
+
+/// The states of a container, each exported so a change of state shows up as one
+/// series dropping to 0 and another rising to 1.
+const CONTAINER_STATES: [&str; 7] = [
+    "created",
+    "running",
+    "paused",
+    "restarting",
+    "removing",
+    "exited",
+    "dead",
+];
+
+/// Signals sent with `docker kill` which end the container, any other signal is
+/// left to the process. Docker reports them by number.
+const STOP_SIGNALS: [&str; 4] = ["2", "3", "9", "15"];
+
+/// How long a container gets to report its stats before it is skipped.
+const STATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Gauges and counters which aren't updated for this long are dropped, so removed
+/// servers disappear. Longer than any sensible scrape interval.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+const HTTP_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+const BUILD_BUCKETS: [f64; 10] = [
+    1.0, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0,
+];
+
+/// CPU time of a container when it was last sampled, to work out its usage since.
+#[derive(Debug, Clone, Copy)]
+struct CpuSample {
+    container: u64,
+    system: u64,
+}
+
+/// Exports the metrics of the node in the Prometheus format. The metrics recorded
+/// as things happen, eg. the requests to the API, are kept by the recorder, the
+/// gauges are sampled when scraped.
+#[derive(Debug)]
+pub struct MetricsManager {
+    handle: PrometheusHandle,
+    docker_manager: Arc<DockerManager>,
+    quota_manager: Arc<QuotaManager>,
+    authenticator: Arc<AuthManager>,
+    /// The previous CPU sample of every running container, by container id.
+    cpu_samples: Mutex<HashMap<String, CpuSample>>,
+}
+
+impl MetricsManager {
+    /// Installs the global recorder, so it can only be called once.
+    pub fn new(
+        docker_manager: Arc<DockerManager>,
+        quota_manager: Arc<QuotaManager>,
+        authenticator: Arc<AuthManager>,
+    ) -> Result<Self> {
+        let handle = PrometheusBuilder::new()
+            .set_buckets_for_metric(
+                Matcher::Full("mastiff_http_request_duration_seconds".to_string()),
+                &HTTP_BUCKETS,
+            )?
+            .set_buckets_for_metric(
+                Matcher::Full("mastiff_image_build_duration_seconds".to_string()),
+                &BUILD_BUCKETS,
+            )?
+            .idle_timeout(
+                MetricKindMask::GAUGE | MetricKindMask::COUNTER,
+                Some(IDLE_TIMEOUT),
+            )
+            .install_recorder()?;
+        describe_metrics();
+
+        // Without the HTTP listener of the exporter, nothing else cleans up.
+        let upkeep = handle.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(5));
+            loop {
+                interval.tick().await;
+                upkeep.run_upkeep();
+            }
+        });
+
+        Ok(Self {
+            handle,
+            docker_manager,
+            quota_manager,
+            authenticator,
+            cpu_samples: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Samples the servers and renders every metric in the text format.
+    pub async fn render(&self) -> String {
+        gauge!("mastiff_ftp_sessions").set(self.authenticator.active_sessions() as f64);
+        if let Err(e) = self.sample_servers().await {
+            tracing::error!("Could not sample the servers for the metrics: {e}");
+        }
+        self.handle.render()
+    }
+
+    /// Spawns a task counting the containers which exit on their own with an error.
+    /// Containers stopped or killed through docker aren't counted.
+    pub fn spawn_crash_watcher(&self) {
+        let docker = self.docker_manager.docker().clone();
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = watch_crashes(&docker).await {
+                    tracing::error!("Lost the docker events: {e}");
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    async fn sample_servers(&self) -> Result<()> {
+        let containers = self
+            .docker_manager
+            .docker()
+            .containers()
+            .list(&ContainerListOpts::builder().all(true).build())
+            .await?;
+
+        let mut samples = JoinSet::new();
+        for container in containers {
+            let labels = container.labels.unwrap_or_default();
+            let (Some(server_id), Some(id)) = (labels.get(SERVER_LABEL), container.id) else {
+                continue;
+            };
+
+            let state = container.state.unwrap_or_default();
+            for known in CONTAINER_STATES {
+                let value = if state == known { 1.0 } else { 0.0 };
+                gauge!("mastiff_server_state", "server_id" => server_id.clone(), "state" => known)
+                    .set(value);
+            }
+
+            // Counters are only touched by crashes, this keeps them from expiring.
+            counter!("mastiff_server_crashes_total", "server_id" => server_id.clone()).increment(0);
+
+            let disk = self.quota_manager.usage(server_id).await;
+            gauge!("mastiff_server_disk_bytes", "server_id" => server_id.clone())
+                .set(disk.used as f64);
+            if let Some(limit) = disk.limit {
+                gauge!("mastiff_server_disk_limit_bytes", "server_id" => server_id.clone())
+                    .set(limit as f64);
+            }
+
+            if state == "running" {
+                let docker = self.docker_manager.docker().clone();
+                let server_id = server_id.clone();
+                samples.spawn(async move {
+                    let stats = container_stats(&docker, &id).await;
+                    (id, server_id, stats)
+                });
+            }
+        }
+
+        let mut cpu_samples = HashMap::new();
+        while let Some(sample) = samples.join_next().await {
+            let (id, server_id, stats) = sample?;
+            match stats {
+                Ok(stats) => {
+                    if let Some(cpu) = self.record_stats(&id, &server_id, &stats) {
+                        cpu_samples.insert(id, cpu);
+                    }
+                }
+                Err(e) => tracing::debug!("Could not get the stats of {server_id}: {e}"),
+            }
+        }
+        // Only the running containers are kept, so the map doesn't grow.
+        *self.cpu_samples.lock().unwrap() = cpu_samples;
+        Ok(())
+    }
+
+    /// Records the stats as returned by docker, returning the CPU sample.
+    fn record_stats(&self, id: &str, server_id: &str, stats: &Value) -> Option<CpuSample> {
+        let number = |pointer: &str| stats.pointer(pointer).and_then(Value::as_u64);
+
+        if let Some(usage) = number("/memory_stats/usage") {
+            // Like `docker stats`, the page cache which can be reclaimed isn't counted.
+            let inactive = number("/memory_stats/stats/inactive_file")
+                .or_else(|| number("/memory_stats/stats/total_inactive_file"))
+                .unwrap_or(0);
+            gauge!("mastiff_server_memory_bytes", "server_id" => server_id.to_string())
+                .set(usage.saturating_sub(inactive) as f64);
+        }
+        if let Some(limit) = number("/memory_stats/limit") {
+            gauge!("mastiff_server_memory_limit_bytes", "server_id" => server_id.to_string())
+                .set(limit as f64);
+        }
+
+        if let Some(networks) = stats.get("networks").and_then(Value::as_object) {
+            let sum = |field: &str| -> u64 {
+                networks
+                    .values()
+                    .filter_map(|network| network.get(field).and_then(Value::as_u64))
+                    .sum()
+            };
+            let labels = [("server_id", server_id.to_string())];
+            counter!("mastiff_server_network_receive_bytes_total", &labels)
+                .absolute(sum("rx_bytes"));
+            counter!("mastiff_server_network_transmit_bytes_total", &labels)
+                .absolute(sum("tx_bytes"));
+        }
+
+        let cpu = CpuSample {
+            container: number("/cpu_stats/cpu_usage/total_usage")?,
+            system: number("/cpu_stats/system_cpu_usage")?,
+        };
+        let cpus = number("/cpu_stats/online_cpus").unwrap_or(1) as f64;
+        let previous = self.cpu_samples.lock().unwrap().get(id).copied();
+        // The usage can only be told from the difference to the previous sample.
+        if let Some(previous) = previous.filter(|previous| cpu.system > previous.system) {
+            let used = cpu.container.saturating_sub(previous.container) as f64;
+            let elapsed = (cpu.system - previous.system) as f64;
+            gauge!("mastiff_server_cpu_usage", "server_id" => server_id.to_string())
+                .set(used / elapsed * cpus);
+        }
+        Some(cpu)
+    }
+}
+
+/// The first stats docker reports for the container, as JSON.
+async fn container_stats(docker: &Docker, id: &str) -> Result<Value> {
+    let container = docker.containers().get(id);
+    let mut stats = container.stats();
+    let stats = tokio::time::timeout(STATS_TIMEOUT, stats.next())
+        .await
+        .map_err(|_| eyre!("Timed out"))?
+        .ok_or_else(|| eyre!("No stats"))??;
+    Ok(serde_json::to_value(stats)?)
+}
+
+async fn watch_crashes(docker: &Docker) -> Result<()> {
+    let mut events = docker.events(&EventsOpts::builder().build());
+    // Only running containers are listed by default.
+    let mut running: HashSet<String> = docker
+        .containers()
+        .list(&ContainerListOpts::builder().build())
+        .await?
+        .into_iter()
+        .filter_map(|container| container.id)
+        .collect();
+    // Running containers which docker was asked to stop, their exit is expected.
+    let mut stopping = HashSet::new();
+
+    while let Some(event) = events.next().await {
+        let event = serde_json::to_value(event?)?;
+        if event["Type"] != "container" {
+            continue;
+        }
+        let actor = &event["Actor"];
+        let (Some(id), Some(server_id)) = (
+            actor["ID"].as_str(),
+            actor["Attributes"][SERVER_LABEL].as_str(),
+        ) else {
+            continue;
+        };
+
+        match event["Action"].as_str() {
+            Some("start") => {
+                running.insert(id.to_string());
+                stopping.remove(id);
+            }
+            Some("stop") if running.contains(id) => {
+                stopping.insert(id.to_string());
+            }
+            // Signals which don't end the container are sent as kills too.
+            Some("kill")
+                if running.contains(id)
+                    && actor["Attributes"]["signal"]
+                        .as_str()
+                        .is_some_and(|signal| STOP_SIGNALS.contains(&signal)) =>
+            {
+                stopping.insert(id.to_string());
+            }
+            Some("die") => {
+                running.remove(id);
+                let expected = stopping.remove(id);
+                let exit_code = actor["Attributes"]["exitCode"].as_str().unwrap_or("0");
+                if !expected && exit_code != "0" {
+                    tracing::warn!("Server {server_id} crashed with exit code {exit_code}");
+                    counter!("mastiff_server_crashes_total", "server_id" => server_id.to_string())
+                        .increment(1);
+                }
+            }
+            Some("destroy") => {
+                running.remove(id);
+                stopping.remove(id);
+            }
+            _ => {}
+        }
+    }
+    Err(eyre!("The event stream ended"))
+}
+
+fn describe_metrics() {
+    describe_gauge!(
+        "mastiff_server_state",
+        "1 for the state the server's container is in, 0 for the others"
+    );
+    describe_gauge!(
+        "mastiff_server_cpu_usage",
+        "CPU cores used by the server since the previous scrape"
+    );
+    describe_gauge!(
+        "mastiff_server_memory_bytes",
+        Unit::Bytes,
+        "Memory used by the server, without the reclaimable page cache"
+    );
+    describe_gauge!(
+        "mastiff_server_memory_limit_bytes",
+        Unit::Bytes,
+        "Memory the server is limited to"
+    );
+    describe_counter!(
+        "mastiff_server_network_receive_bytes_total",
+        Unit::Bytes,
+        "Bytes received by the server since its container started"
+    );
+    describe_counter!(
+        "mastiff_server_network_transmit_bytes_total",
+        Unit::Bytes,
+        "Bytes sent by the server since its container started"
+    );
+    describe_gauge!(
+        "mastiff_server_disk_bytes",
+        Unit::Bytes,
+        "Bytes used by the server's data directory"
+    );
+    describe_gauge!(
+        "mastiff_server_disk_limit_bytes",
+        Unit::Bytes,
+        "Disk limit of the server"
+    );
+    describe_counter!(
+        "mastiff_server_crashes_total",
+        "Times the server exited with an error without being stopped"
+    );
+    describe_histogram!(
+        "mastiff_http_request_duration_seconds",
+        Unit::Seconds,
+        "Time taken to answer the requests to the API, by route"
+    );
+    describe_gauge!("mastiff_ftp_sessions", "Active FTP and SFTP sessions");
+    describe_counter!(
+        "mastiff_ftp_transferred_bytes_total",
+        Unit::Bytes,
+        "Bytes uploaded and downloaded over FTP and SFTP"
+    );
+    describe_histogram!(
+        "mastiff_image_build_duration_seconds",
+        Unit::Seconds,
+        "Time taken to build or pull the image of a recipe"
+    );
+}
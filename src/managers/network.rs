@@ -0,0 +1,546 @@
+use std::{collections::HashMap, path::PathBuf, sync::Arc, time::Duration};
+
+use docker_api::{
+    models::ContainerSummary,
+    opts::{
+        ContainerConnectionOpts, ContainerDisconnectionOpts, ContainerFilter, ContainerListOpts,
+        EventsOpts, NetworkCreateOpts, NetworkFilter, NetworkListOpts,
+    },
+    Docker,
+};
+use eyre::{eyre, Result};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs,
+    sync::{Mutex, RwLock},
+};
+use tokio_stream::StreamExt;
+use tracing::instrument;
+
+use super::{
+    docker::DockerManager,
+    firewall::{self, EgressPolicy},
+};
+use crate::config::{ContainerManagerSettings, NetworkIsolation};
+
+/// Name of the network shared by all the servers in [`NetworkIsolation::Shared`] mode.
+const SHARED_NETWORK: &str = "mastiff-shared";
+
+/// Docker's default bridge, where every container can reach the others.
+const DEFAULT_NETWORK: &str = "bridge";
+
+const SERVER_LABEL: &str = "mastiff.server-id";
+
+#[derive(Debug, thiserror::Error)]
+pub enum NetworkError {
+    #[error("No container found for server {0}")]
+    ServerNotFound(String),
+    #[error("Only servers owned by the same user can be linked")]
+    DifferentOwners,
+    #[error("Invalid egress policy: {0}")]
+    InvalidPolicy(String),
+}
+
+/// Manages the bridge networks the server containers are attached to. Every network
+/// created by mastiff is labelled with `mastiff.managed`, the networks owned by a
+/// single server are also labelled with `mastiff.server-id`.
+///
+/// It also applies the egress policies of the servers, which are persisted so they
+/// can be reapplied on startup.
+#[derive(Debug)]
+pub struct NetworkManager {
+    docker: Docker,
+    isolation: NetworkIsolation,
+    egress_policy_file: PathBuf,
+    egress_policies: RwLock<HashMap<String, EgressPolicy>>,
+    /// Held while the egress rules are changed, as the API and the container watcher
+    /// may apply the policy of a server at the same time.
+    firewall_lock: Mutex<()>,
+}
+
+impl NetworkManager {
+    pub fn new(docker_manager: Arc<DockerManager>, settings: &ContainerManagerSettings) -> Self {
+        Self {
+            docker: docker_manager.docker().clone(),
+            isolation: settings.network_isolation,
+            egress_policy_file: settings.egress_policy_file.clone(),
+            egress_policies: RwLock::new(HashMap::new()),
+            firewall_lock: Mutex::new(()),
+        }
+    }
+
+    /// Creates the network the server must be attached to, if it doesn't exist yet,
+    /// and returns its name.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn ensure_server_network(&self, server_id: &str) -> Result<String> {
+        match self.isolation {
+            NetworkIsolation::PerServer => self.ensure_network(Some(server_id)).await,
+            NetworkIsolation::Shared => self.ensure_network(None).await,
+        }
+    }
+
+    /// Removes the network and the egress rules of the server, after its container
+    /// is deleted. The servers linked to it are disconnected. The stored policy is
+    /// kept for a recreated container, it is dropped on startup if there is none.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn remove_server_network(&self, server_id: &str) -> Result<()> {
+        firewall::remove_egress_policy(server_id).await?;
+
+        let networks = self.docker.networks();
+        let name = network_name(Some(server_id));
+
+        if let Ok(network) = networks.get(&name).inspect().await {
+            for container_id in network.containers.unwrap_or_default().keys() {
+                networks
+                    .get(&name)
+                    .disconnect(
+                        &ContainerDisconnectionOpts::builder(container_id)
+                            .force(true)
+                            .build(),
+                    )
+                    .await?;
+            }
+            networks.get(&name).delete().await?;
+            firewall::unblock_host_access(&bridge_of(network.options, server_id)).await?;
+        }
+        Ok(())
+    }
+
+    /// Follows the lifecycle of the server containers, which are created outside of
+    /// mastiff. New containers are moved to the network of their server and get their
+    /// egress policy when started, the network of a deleted server is removed.
+    pub fn spawn_container_watcher(self: Arc<Self>) {
+        tokio::spawn(async move {
+            loop {
+                if let Err(e) = self.watch_containers().await {
+                    tracing::error!("Lost the docker events: {e}");
+                }
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    async fn watch_containers(&self) -> Result<()> {
+        let mut events = self.docker.events(&EventsOpts::builder().build());
+
+        // The containers created while the events weren't followed.
+        let containers = self
+            .docker
+            .containers()
+            .list(
+                &ContainerListOpts::builder()
+                    .all(true)
+                    .filter([ContainerFilter::LabelKey(SERVER_LABEL.to_string())])
+                    .build(),
+            )
+            .await?;
+        for container in containers {
+            let labels = container.labels.unwrap_or_default();
+            let (Some(server_id), Some(id)) = (labels.get(SERVER_LABEL), container.id) else {
+                continue;
+            };
+            if let Err(e) = self.prepare_server(server_id, &id).await {
+                tracing::error!("Could not set up the network of server {server_id}: {e}");
+            }
+        }
+
+        while let Some(event) = events.next().await {
+            let event = serde_json::to_value(event?)?;
+            if let Err(e) = self.handle_event(&event).await {
+                tracing::error!("Could not update the network of a server: {e}");
+            }
+        }
+        Err(eyre!("The event stream ended"))
+    }
+
+    async fn handle_event(&self, event: &Value) -> Result<()> {
+        let actor = &event["Actor"];
+        match (event["Type"].as_str(), event["Action"].as_str()) {
+            // The egress rules match the addresses of the container on its networks.
+            (Some("network"), Some("connect" | "disconnect")) => {
+                let Some(id) = actor["Attributes"]["container"].as_str() else {
+                    return Ok(());
+                };
+                // Containers are disconnected as they are deleted.
+                let Ok(container) = self.docker.containers().get(id).inspect().await else {
+                    return Ok(());
+                };
+                let labels = container
+                    .config
+                    .and_then(|config| config.labels)
+                    .unwrap_or_default();
+                match labels.get(SERVER_LABEL) {
+                    Some(server_id) => self.reapply_egress_policy(server_id).await,
+                    None => Ok(()),
+                }
+            }
+            (Some("container"), action) => {
+                let (Some(id), Some(server_id)) = (
+                    actor["ID"].as_str(),
+                    actor["Attributes"][SERVER_LABEL].as_str(),
+                ) else {
+                    return Ok(());
+                };
+
+                match action {
+                    Some("create") => self.attach_server(server_id, id).await,
+                    Some("start") => self.prepare_server(server_id, id).await,
+                    // A new container may already have replaced the deleted one.
+                    Some("destroy") if self.find_container(server_id).await.is_err() => {
+                        self.remove_server_network(server_id).await
+                    }
+                    _ => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Attaches the container and applies the egress policy of the server, whose
+    /// rules depend on the addresses of the container.
+    async fn prepare_server(&self, server_id: &str, container_id: &str) -> Result<()> {
+        self.attach_server(server_id, container_id).await?;
+        self.reapply_egress_policy(server_id).await
+    }
+
+    async fn reapply_egress_policy(&self, server_id: &str) -> Result<()> {
+        let policy = self.egress_policies.read().await.get(server_id).cloned();
+        match policy {
+            Some(policy) => self.apply_egress_policy(server_id, &policy).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Connects a container of the server to its network, and disconnects it from
+    /// docker's default bridge.
+    #[instrument(skip(self), level = "debug")]
+    async fn attach_server(&self, server_id: &str, container_id: &str) -> Result<()> {
+        let network_name = self.ensure_server_network(server_id).await?;
+        let networks = self
+            .docker
+            .containers()
+            .get(container_id)
+            .inspect()
+            .await?
+            .network_settings
+            .and_then(|settings| settings.networks)
+            .unwrap_or_default();
+
+        if !networks.contains_key(&network_name) {
+            self.docker
+                .networks()
+                .get(&network_name)
+                .connect(
+                    &ContainerConnectionOpts::builder(container_id)
+                        .aliases([server_id])
+                        .build(),
+                )
+                .await?;
+        }
+        if networks.contains_key(DEFAULT_NETWORK) {
+            self.docker
+                .networks()
+                .get(DEFAULT_NETWORK)
+                .disconnect(
+                    &ContainerDisconnectionOpts::builder(container_id)
+                        .force(true)
+                        .build(),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Allows `target` to reach `server` by attaching it to the network of `server`,
+    /// where it is reachable through its server id. Both the containers have to be
+    /// labelled with the same `mastiff.owner`.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn link_servers(&self, server_id: &str, target_id: &str) -> Result<()> {
+        let server = self.find_container(server_id).await?;
+        let target = self.find_container(target_id).await?;
+        ensure_same_owner(&server, &target)?;
+
+        // In shared mode, the server needs a network of its own to be linked to.
+        let network_name = self.ensure_network(Some(server_id)).await?;
+        let network = self.docker.networks().get(&network_name);
+
+        for (container, id) in [(&server, server_id), (&target, target_id)] {
+            if self.is_connected(container, &network_name) {
+                continue;
+            }
+            network
+                .connect(
+                    &ContainerConnectionOpts::builder(container.id.as_deref().unwrap())
+                        .aliases([id])
+                        .build(),
+                )
+                .await?;
+        }
+
+        tracing::info!("Linked server {target_id} to {server_id}");
+        Ok(())
+    }
+
+    /// Removes a link created by [`NetworkManager::link_servers`].
+    #[instrument(skip(self), level = "debug")]
+    pub async fn unlink_servers(&self, server_id: &str, target_id: &str) -> Result<()> {
+        let target = self.find_container(target_id).await?;
+        let network_name = network_name(Some(server_id));
+
+        if self.is_connected(&target, &network_name) {
+            self.docker
+                .networks()
+                .get(&network_name)
+                .disconnect(
+                    &ContainerDisconnectionOpts::builder(target.id.as_deref().unwrap())
+                        .force(true)
+                        .build(),
+                )
+                .await?;
+        }
+
+        tracing::info!("Unlinked server {target_id} from {server_id}");
+        Ok(())
+    }
+
+    /// Removes the networks of servers which no longer have a container.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn cleanup_orphaned_networks(&self) -> Result<()> {
+        let networks = self
+            .docker
+            .networks()
+            .list(
+                &NetworkListOpts::builder()
+                    .filter([NetworkFilter::LabelKey(SERVER_LABEL.to_string())])
+                    .build(),
+            )
+            .await?;
+
+        for network in networks {
+            let (Some(name), Some(labels)) = (network.name, network.labels) else {
+                continue;
+            };
+            let server_id = &labels[SERVER_LABEL];
+
+            if self.find_container(server_id).await.is_ok() {
+                continue;
+            }
+
+            tracing::info!("Removing orphaned network {name}");
+            if let Err(e) = self.remove_server_network(server_id).await {
+                tracing::warn!("Could not remove orphaned network {name}: {e}");
+            }
+        }
+        Ok(())
+    }
+
+    /// The egress policy of a server. Servers without a policy can connect anywhere.
+    pub async fn get_egress_policy(&self, server_id: &str) -> EgressPolicy {
+        self.egress_policies
+            .read()
+            .await
+            .get(server_id)
+            .cloned()
+            .unwrap_or(EgressPolicy::AllowAll)
+    }
+
+    /// Applies and stores the egress policy of a server.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn set_egress_policy(&self, server_id: &str, policy: EgressPolicy) -> Result<()> {
+        policy
+            .validate()
+            .map_err(|e| NetworkError::InvalidPolicy(format!("{e:#}")))?;
+        self.apply_egress_policy(server_id, &policy).await?;
+
+        self.egress_policies
+            .write()
+            .await
+            .insert(server_id.to_string(), policy);
+        self.save_egress_policies().await
+    }
+
+    /// Loads the stored egress policies and brings the firewall in line with them.
+    /// Rules of servers which no longer exist are removed.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn reconcile_egress_policies(&self) -> Result<()> {
+        let mut policies: HashMap<String, EgressPolicy> =
+            match fs::read(&self.egress_policy_file).await {
+                Ok(data) => serde_json::from_slice(&data)?,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+                Err(e) => return Err(e.into()),
+            };
+
+        let mut orphaned = Vec::new();
+        for (server_id, policy) in &policies {
+            if self.find_container(server_id).await.is_err() {
+                orphaned.push(server_id.clone());
+                continue;
+            }
+            if let Err(e) = self.apply_egress_policy(server_id, policy).await {
+                tracing::error!("Could not apply the egress policy of {server_id}: {e}");
+            }
+        }
+
+        for server_id in orphaned {
+            tracing::info!("Removing egress policy of deleted server {server_id}");
+            policies.remove(&server_id);
+        }
+        // Only after the policies are applied, so servers whose chains were named
+        // by an older version are never left without rules.
+        firewall::retain_egress_policies(policies.keys().map(String::as_str)).await?;
+
+        *self.egress_policies.write().await = policies;
+        self.save_egress_policies().await
+    }
+
+    /// Applies the egress policy to the server's traffic leaving the networks its
+    /// container is on. The rules match the addresses of the container, so they are
+    /// reapplied whenever it is started or connected to a network.
+    pub async fn apply_egress_policy(&self, server_id: &str, policy: &EgressPolicy) -> Result<()> {
+        let _guard = self.firewall_lock.lock().await;
+        let endpoints = self
+            .find_container(server_id)
+            .await?
+            .network_settings
+            .and_then(|settings| settings.networks)
+            .unwrap_or_default();
+
+        // Linked servers share a bridge, so the traffic is told apart by its source.
+        let mut sources = Vec::new();
+        for (name, endpoint) in endpoints {
+            // Stopped containers have no address, and get the rules once started.
+            let Some(address) = endpoint.ip_address.filter(|address| !address.is_empty()) else {
+                continue;
+            };
+            let network = self.docker.networks().get(&name).inspect().await?;
+            if network.driver.as_deref() != Some("bridge") {
+                continue;
+            }
+
+            let bridge = match network.labels.unwrap_or_default().get(SERVER_LABEL) {
+                Some(owner) => bridge_of(network.options, owner),
+                // Docker names the bridges of networks without the option by their id.
+                None => network
+                    .options
+                    .and_then(|mut options| options.remove("com.docker.network.bridge.name"))
+                    .or_else(|| network.id.map(|id| format!("br-{}", &id[..12])))
+                    .ok_or_else(|| eyre!("Network {name} has no bridge"))?,
+            };
+            sources.push((bridge, address));
+        }
+
+        firewall::apply_egress_policy(server_id, &sources, policy).await
+    }
+
+    async fn save_egress_policies(&self) -> Result<()> {
+        let policies = self.egress_policies.read().await;
+        fs::write(&self.egress_policy_file, serde_json::to_vec(&*policies)?).await?;
+        Ok(())
+    }
+
+    /// Creates the network owned by `server_id`, or the shared network if `None`.
+    async fn ensure_network(&self, server_id: Option<&str>) -> Result<String> {
+        let networks = self.docker.networks();
+        let name = network_name(server_id);
+
+        if networks.get(&name).inspect().await.is_ok() {
+            return Ok(name);
+        }
+
+        let bridge = bridge_name(server_id);
+        let mut labels = HashMap::from([("mastiff.managed", "true")]);
+        if let Some(server_id) = server_id {
+            labels.insert(SERVER_LABEL, server_id);
+        }
+
+        // Inter-container communication is only allowed on the networks owned by a
+        // server, whose members are the server and the servers linked to it.
+        let icc = if server_id.is_some() { "true" } else { "false" };
+
+        networks
+            .create(
+                &NetworkCreateOpts::builder(&name)
+                    .driver("bridge")
+                    .labels(labels)
+                    .options([
+                        ("com.docker.network.bridge.name", bridge.as_str()),
+                        ("com.docker.network.bridge.enable_icc", icc),
+                    ])
+                    .build(),
+            )
+            .await?;
+        firewall::block_host_access(&bridge).await?;
+
+        tracing::debug!("Created network {name} on bridge {bridge}");
+        Ok(name)
+    }
+
+    async fn find_container(&self, server_id: &str) -> Result<ContainerSummary> {
+        self.docker
+            .containers()
+            .list(
+                &ContainerListOpts::builder()
+                    .all(true)
+                    .filter([ContainerFilter::Label(
+                        SERVER_LABEL.to_string(),
+                        server_id.to_string(),
+                    )])
+                    .build(),
+            )
+            .await?
+            .into_iter()
+            .next()
+            .ok_or_else(|| NetworkError::ServerNotFound(server_id.to_string()).into())
+    }
+
+    fn is_connected(&self, container: &ContainerSummary, network_name: &str) -> bool {
+        container
+            .network_settings
+            .as_ref()
+            .and_then(|settings| settings.networks.as_ref())
+            .is_some_and(|networks| networks.contains_key(network_name))
+    }
+}
+
+/// Containers created without the `mastiff.owner` label can't be linked, as their
+/// owner is unknown.
+fn ensure_same_owner(server: &ContainerSummary, target: &ContainerSummary) -> Result<()> {
+    let owner = |container: &ContainerSummary| {
+        container
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get("mastiff.owner").cloned())
+    };
+
+    match (owner(server), owner(target)) {
+        (Some(a), Some(b)) if a == b => Ok(()),
+        _ => Err(NetworkError::DifferentOwners.into()),
+    }
+}
+
+fn network_name(server_id: Option<&str>) -> String {
+    match server_id {
+        Some(server_id) => format!("mastiff-{server_id}"),
+        None => SHARED_NETWORK.to_string(),
+    }
+}
+
+/// Linux limits interface names to 15 characters, so the server id is hashed.
+fn bridge_name(server_id: Option<&str>) -> String {
+    match server_id {
+        Some(server_id) => {
+            let hash = hex::encode(Sha256::digest(server_id));
+            format!("mst-{}", &hash[..11])
+        }
+        None => "mst-shared".to_string(),
+    }
+}
+
+/// The bridge of an existing network of the server. Networks created by older
+/// versions are named differently, so the name is read from the network.
+fn bridge_of(options: Option<HashMap<String, String>>, server_id: &str) -> String {
+    options
+        .and_then(|mut options| options.remove("com.docker.network.bridge.name"))
+        .unwrap_or_else(|| bridge_name(Some(server_id)))
+}
@@ -0,0 +1,451 @@
+use std::{
+    collections::HashMap,
+    io::{self, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use docker_api::{
+    opts::{ContainerFilter, ContainerListOpts, ContainerStopOpts},
+    Docker,
+};
+use eyre::Result;
+use serde::Serialize;
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::RwLock,
+};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::docker::DockerManager;
+use crate::config::QuotaSettings;
+
+#[derive(Debug, thiserror::Error)]
+pub enum QuotaError {
+    #[error("Server {0} has exceeded its disk limit")]
+    Exceeded(String),
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct DiskUsage {
+    /// Bytes used by the server's data directory, as of the last scan plus the
+    /// uploads since.
+    pub used: u64,
+    /// If `None`, the server has no limit.
+    pub limit: Option<u64>,
+}
+
+impl DiskUsage {
+    /// Bytes which can still be written, `None` if unlimited.
+    pub fn remaining(&self) -> Option<u64> {
+        self.limit.map(|limit| limit.saturating_sub(self.used))
+    }
+}
+
+/// Tracks the disk usage of the servers' data directories against their limits.
+/// Usage is measured by a background scanner, the limits are set by the panel and
+/// persisted so they survive restarts.
+#[derive(Debug)]
+pub struct QuotaManager {
+    docker: Docker,
+    data_directory: PathBuf,
+    settings: QuotaSettings,
+    limits: RwLock<HashMap<String, u64>>,
+    usage: RwLock<HashMap<String, u64>>,
+}
+
+impl QuotaManager {
+    pub fn new(
+        docker_manager: Arc<DockerManager>,
+        data_directory: impl Into<PathBuf>,
+        settings: QuotaSettings,
+    ) -> Self {
+        Self {
+            docker: docker_manager.docker().clone(),
+            data_directory: data_directory.into(),
+            settings,
+            limits: RwLock::new(HashMap::new()),
+            usage: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Loads the stored limits.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn load_limits(&self) -> Result<()> {
+        let limits = match fs::read(&self.settings.limit_file).await {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        *self.limits.write().await = limits;
+        Ok(())
+    }
+
+    /// Sets the disk limit of the server in bytes, `None` removes it.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn set_limit(&self, server_id: &str, limit: Option<u64>) -> Result<()> {
+        let mut limits = self.limits.write().await;
+        match limit {
+            Some(limit) => limits.insert(server_id.to_string(), limit),
+            None => limits.remove(server_id),
+        };
+        fs::write(&self.settings.limit_file, serde_json::to_vec(&*limits)?).await?;
+        Ok(())
+    }
+
+    pub async fn usage(&self, server_id: &str) -> DiskUsage {
+        DiskUsage {
+            used: self.usage.read().await.get(server_id).copied().unwrap_or(0),
+            limit: self.limits.read().await.get(server_id).copied(),
+        }
+    }
+
+    /// Fails with [`QuotaError::Exceeded`] if the server can't write any more.
+    pub async fn ensure_within_limit(&self, server_id: &str) -> Result<()> {
+        if self.usage(server_id).await.remaining() == Some(0) {
+            return Err(QuotaError::Exceeded(server_id.to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Accounts for the bytes written since the last scan.
+    pub async fn add_usage(&self, server_id: &str, bytes: u64) {
+        *self
+            .usage
+            .write()
+            .await
+            .entry(server_id.to_string())
+            .or_default() += bytes;
+    }
+
+    /// Accounts for the bytes freed since the last scan, eg. by overwriting a file.
+    pub async fn remove_usage(&self, server_id: &str, bytes: u64) {
+        if let Some(used) = self.usage.write().await.get_mut(server_id) {
+            *used = used.saturating_sub(bytes);
+        }
+    }
+
+    /// Spawns the task measuring the disk usage every `scan_interval` seconds.
+    pub fn spawn_scanner(self: Arc<Self>) {
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval(Duration::from_secs(self.settings.scan_interval));
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.scan().await {
+                    tracing::error!("Disk usage scan failed: {e}");
+                }
+            }
+        });
+    }
+
+    /// Measures the data directory of every server, then stops the servers which
+    /// are too far over their limit.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn scan(&self) -> Result<()> {
+        let data_directory = self.data_directory.clone();
+        let usage = tokio::task::spawn_blocking(move || -> io::Result<_> {
+            let mut usage = HashMap::new();
+            for entry in std::fs::read_dir(data_directory)? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    let server_id = entry.file_name().to_string_lossy().to_string();
+                    usage.insert(server_id, directory_size(&entry.path())?);
+                }
+            }
+            Ok(usage)
+        })
+        .await??;
+
+        let Some(stop_ratio) = self.settings.stop_ratio else {
+            *self.usage.write().await = usage;
+            return Ok(());
+        };
+
+        let limits = self.limits.read().await.clone();
+        for (server_id, used) in &usage {
+            let Some(limit) = limits.get(server_id) else {
+                continue;
+            };
+            if *used as f64 > *limit as f64 * stop_ratio {
+                tracing::warn!("Server {server_id} uses {used} of {limit} bytes, stopping it");
+                if let Err(e) = self.stop_server(server_id).await {
+                    tracing::error!("Could not stop server {server_id}: {e}");
+                }
+            }
+        }
+
+        *self.usage.write().await = usage;
+        Ok(())
+    }
+
+    async fn stop_server(&self, server_id: &str) -> Result<()> {
+        let containers = self
+            .docker
+            .containers()
+            .list(
+                &ContainerListOpts::builder()
+                    .filter([ContainerFilter::Label(
+                        "mastiff.server-id".to_string(),
+                        server_id.to_string(),
+                    )])
+                    .build(),
+            )
+            .await?;
+
+        for container in containers {
+            if let Some(id) = container.id {
+                self.docker
+                    .containers()
+                    .get(id)
+                    .stop(&ContainerStopOpts::default())
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The bytes the scanner counts for a file or a directory, 0 if it doesn't exist.
+/// Measured before deleting it, to give the space back with
+/// [`QuotaManager::remove_usage`].
+pub async fn path_size(path: &Path) -> u64 {
+    let path = path.to_path_buf();
+    tokio::task::spawn_blocking(move || match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.is_dir() => directory_size(&path).unwrap_or(0),
+        Ok(metadata) => metadata.len(),
+        Err(_) => 0,
+    })
+    .await
+    .unwrap_or(0)
+}
+
+/// Sums the sizes of the files below `path`. Symlinks are not followed, so a link to
+/// a large file outside of the directory isn't counted. Files removed while scanning
+/// are skipped, as the server keeps running.
+fn directory_size(path: &Path) -> io::Result<u64> {
+    let skip_missing = |result: io::Result<u64>| match result {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        result => result,
+    };
+
+    let entries = match std::fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut size = 0;
+    for entry in entries {
+        let entry = entry?;
+        size += skip_missing(entry.metadata().and_then(|metadata| {
+            if metadata.is_dir() {
+                directory_size(&entry.path())
+            } else {
+                Ok(metadata.len())
+            }
+        }))?;
+    }
+    Ok(size)
+}
+
+/// Fails once more than `limit` bytes are read, so a single write can't go over the
+/// disk limit. Whether that happened can be checked with the flag from
+/// [`Self::exceeded`], as the error may be wrapped by the writer.
+pub struct LimitedReader<R> {
+    inner: R,
+    /// `None` if unlimited.
+    remaining: Option<u64>,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<R> LimitedReader<R> {
+    pub fn new(inner: R, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            remaining: limit,
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set once the reader had more than `limit` bytes.
+    pub fn exceeded(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.exceeded)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for LimitedReader<R> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let Some(remaining) = self.remaining else {
+            return Pin::new(&mut self.inner).poll_read(cx, buf);
+        };
+
+        if remaining == 0 {
+            // Only fails if there actually is more to read.
+            let mut byte = [0; 1];
+            let mut probe = ReadBuf::new(&mut byte);
+            ready!(Pin::new(&mut self.inner).poll_read(cx, &mut probe))?;
+            if probe.filled().is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            self.exceeded.store(true, Ordering::Relaxed);
+            return Poll::Ready(Err(io::Error::other("The disk limit was exceeded")));
+        }
+
+        let max = buf
+            .remaining()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        let mut limited = ReadBuf::new(buf.initialize_unfilled_to(max));
+        ready!(Pin::new(&mut self.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+        buf.advance(read);
+        self.remaining = Some(remaining - read as u64);
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Fails writes which would make the file larger than `limit` bytes, for writers
+/// which produce a file instead of copying a stream, eg. archives. The file has to
+/// start out empty. Like with [`LimitedReader`], the flag from [`Self::exceeded`]
+/// tells if the limit was hit.
+pub struct LimitedWriter<W> {
+    inner: W,
+    /// Offset of the next write.
+    position: u64,
+    /// `None` if unlimited.
+    limit: Option<u64>,
+    exceeded: Arc<AtomicBool>,
+}
+
+impl<W> LimitedWriter<W> {
+    pub fn new(inner: W, limit: Option<u64>) -> Self {
+        Self {
+            inner,
+            position: 0,
+            limit,
+            exceeded: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Set once a write would have gone past `limit`.
+    pub fn exceeded(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.exceeded)
+    }
+
+    fn check(&self, len: usize) -> io::Result<()> {
+        match self.limit {
+            Some(limit) if self.position.saturating_add(len as u64) > limit => {
+                self.exceeded.store(true, Ordering::Relaxed);
+                Err(io::Error::other("The disk limit was exceeded"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> Write for LimitedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.check(buf.len())?;
+        let written = self.inner.write(buf)?;
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for LimitedWriter<W> {
+    fn seek(&mut self, position: SeekFrom) -> io::Result<u64> {
+        self.position = self.inner.seek(position)?;
+        Ok(self.position)
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for LimitedWriter<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.check(buf.len())?;
+        let written = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.position += written as u64;
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    async fn read_limited(data: &[u8], limit: Option<u64>) -> (io::Result<Vec<u8>>, bool) {
+        let mut reader = LimitedReader::new(data, limit);
+        let exceeded = reader.exceeded();
+        let mut read = Vec::new();
+        let result = reader.read_to_end(&mut read).await.map(|_| read);
+        (result, exceeded.load(Ordering::Relaxed))
+    }
+
+    #[tokio::test]
+    async fn reads_up_to_the_limit() {
+        let (result, exceeded) = read_limited(b"abcd", Some(4)).await;
+        assert_eq!(result.unwrap(), b"abcd");
+        assert!(!exceeded);
+
+        let (result, exceeded) = read_limited(b"abcd", None).await;
+        assert_eq!(result.unwrap(), b"abcd");
+        assert!(!exceeded);
+    }
+
+    #[tokio::test]
+    async fn fails_past_the_limit() {
+        let (result, exceeded) = read_limited(b"abcd", Some(3)).await;
+        assert!(result.is_err());
+        assert!(exceeded);
+    }
+
+    #[test]
+    fn missing_directories_are_empty() {
+        let dir = tempfile::TempDir::new().unwrap();
+        std::fs::write(dir.path().join("file"), "abc").unwrap();
+        assert_eq!(directory_size(dir.path()).unwrap(), 3);
+        assert_eq!(directory_size(&dir.path().join("missing")).unwrap(), 0);
+    }
+
+    #[test]
+    fn writes_are_capped() {
+        let mut writer = LimitedWriter::new(io::Cursor::new(Vec::new()), Some(4));
+        let exceeded = writer.exceeded();
+        writer.write_all(b"abc").unwrap();
+        // Rewriting what is already there doesn't grow the file.
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"abcd").unwrap();
+        assert!(!exceeded.load(Ordering::Relaxed));
+
+        assert!(writer.write_all(b"e").is_err());
+        assert!(exceeded.load(Ordering::Relaxed));
+    }
+}
@@ -1,19 +1,39 @@
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
     path::{Path, PathBuf},
     sync::Arc,
+    time::{Duration, Instant},
 };
 
 use async_compression::tokio::bufread::GzipDecoder;
 use config::{Config, ConfigError, File};
-use eyre::Result;
+use eyre::{Result, WrapErr};
 use serde::Deserialize;
 use tokio::{fs, io::AsyncBufRead, sync::RwLock};
 use tokio_stream::{wrappers::ReadDirStream, StreamExt};
 use tokio_tar::Archive;
 use tracing::instrument;
 
-use super::docker::{DockerManager, Image, ImageSource};
+use super::docker::{
+    is_valid_recipe_name, is_valid_version, BuildOptions, DockerManager, GcReport, Image,
+    ImageSource,
+};
+use crate::config::ImageGcSettings;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RecipeError {
+    #[error("Recipe '{0}' not found")]
+    NotFound(String),
+    /// The uploaded file is not a valid `tar.gz` archive.
+    #[error("Invalid recipe archive: {0}")]
+    InvalidArchive(String),
+    /// The `recipe.toml` is missing or invalid.
+    #[error("Invalid recipe: {0}")]
+    InvalidRecipe(String),
+    /// The name can't be used as a directory and image name.
+    #[error("Invalid recipe name: '{0}'")]
+    InvalidName(String),
+}
 
 #[derive(Debug, Deserialize)]
 pub enum ImageType {
@@ -46,6 +66,9 @@ pub struct Recipe {
     /// `/home/container`, the recipe must contain the config file with the correct
     /// name if this is set.
     pub config_path: Option<PathBuf>,
+    /// Options used when building a local image.
+    #[serde(default)]
+    pub build: BuildOptions,
 }
 
 impl Recipe {
@@ -71,23 +94,45 @@ impl RecipeManager {
         }
     }
 
-    /// Parse and build the recipe.
+    /// Parse and build the recipe. `build_args` override the build arguments set
+    /// in the recipe.
     #[instrument(skip(self), level = "debug")]
-    pub async fn build_recipe(&self, recipe_path: &Path) -> Result<()> {
-        let recipe_config = Recipe::parse(recipe_path.join("recipe.toml").to_str().unwrap())?;
+    pub async fn build_recipe(
+        &self,
+        recipe_path: &Path,
+        build_args: HashMap<String, String>,
+    ) -> Result<()> {
+        let mut recipe_config = Recipe::parse(recipe_path.join("recipe.toml").to_str().unwrap())
+            .map_err(|e| RecipeError::InvalidRecipe(format!("{e:#}")))?;
+        recipe_config.build.args.extend(build_args);
+        if !is_valid_version(&recipe_config.version) {
+            return Err(RecipeError::InvalidRecipe(format!(
+                "'{}' can't be used as an image tag",
+                recipe_config.version
+            ))
+            .into());
+        }
 
-        match recipe_config.image {
+        let started = Instant::now();
+        let source = match recipe_config.image {
             ImageType::Registry(name) => {
                 self.docker_manager
                     .get_image(Image::new_registry(name), true)
-                    .await?
+                    .await?;
+                "registry"
             }
             ImageType::Local => {
                 self.docker_manager
-                    .get_image(Image::new_local(recipe_path), true)
-                    .await?
+                    .get_image(
+                        Image::new_local(recipe_path, recipe_config.version, recipe_config.build),
+                        true,
+                    )
+                    .await?;
+                "local"
             }
         };
+        metrics::histogram!("mastiff_image_build_duration_seconds", "source" => source)
+            .record(started.elapsed().as_secs_f64());
 
         Ok(())
     }
@@ -100,15 +145,21 @@ impl RecipeManager {
         recipe_name: &str,
         file_stream: impl AsyncBufRead + Unpin + Send,
     ) -> Result<PathBuf> {
+        if !is_valid_recipe_name(recipe_name) {
+            return Err(RecipeError::InvalidName(recipe_name.to_string()).into());
+        }
         let recipe_path = self.recipe_directory.join(recipe_name);
         let decoder = GzipDecoder::new(file_stream);
         let mut unarchiver = Archive::new(decoder);
 
-        let mut entries = unarchiver.entries()?;
+        let invalid = |e: std::io::Error| RecipeError::InvalidArchive(e.to_string());
+        let mut entries = unarchiver.entries().map_err(invalid)?;
 
         while let Some(file) = entries.next().await {
-            let mut file = file?;
-            file.unpack_in(&recipe_path).await?;
+            let mut file = file.map_err(invalid)?;
+            file.unpack_in(&recipe_path)
+                .await
+                .wrap_err_with(|| format!("Could not unpack into {}", recipe_path.display()))?;
         }
 
         Ok(recipe_path)
@@ -136,17 +187,68 @@ impl RecipeManager {
         Ok(files)
     }
 
-    /// Deletes a recipe if its present in the store.
+    /// Deletes a recipe and its images.
     #[instrument(skip(self), level = "debug")]
     pub async fn delete_recipe(&self, recipe_name: &str) -> Result<()> {
         let recipes = self.list_recipes().await?;
         tracing::debug!("Found recipes: {recipes:?}");
 
-        if recipes.iter().any(|el| el == recipe_name) {
-            fs::remove_dir_all(self.recipe_directory.join(recipe_name)).await?;
+        if !recipes.iter().any(|el| el == recipe_name) {
+            return Err(RecipeError::NotFound(recipe_name.to_string()).into());
         }
+        fs::remove_dir_all(self.recipe_directory.join(recipe_name)).await?;
         // Try cleaning up any danglin images
         self.docker_manager.delete_image(recipe_name).await?;
         Ok(())
     }
+
+    /// Switches a locally built recipe back to the image of its previous version.
+    /// Returns the version which is now active.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn rollback_recipe(&self, recipe_name: &str) -> Result<String> {
+        self.docker_manager.rollback_image(recipe_name).await
+    }
+
+    /// Removes the images which are not required by the installed recipes.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn collect_image_garbage(&self, dry_run: bool) -> Result<GcReport> {
+        let recipes = self.list_recipes().await?;
+        self.docker_manager.collect_garbage(&recipes, dry_run).await
+    }
+
+    /// Spawns a task which collects image garbage according to the configured policy.
+    pub fn spawn_image_gc(self: Arc<Self>, settings: ImageGcSettings) {
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(settings.interval));
+
+            loop {
+                interval.tick().await;
+
+                if let Some(threshold) = settings.disk_usage_threshold {
+                    match self.docker_manager.image_disk_usage().await {
+                        Ok(usage) if usage < threshold => continue,
+                        Ok(_) => {}
+                        Err(e) => {
+                            tracing::error!("Could not calculate image disk usage: {e}");
+                            continue;
+                        }
+                    }
+                }
+
+                match self.collect_image_garbage(settings.dry_run).await {
+                    Ok(report) => tracing::info!(
+                        "Image garbage collection {} {} images, reclaiming {} bytes",
+                        if report.dry_run {
+                            "would remove"
+                        } else {
+                            "removed"
+                        },
+                        report.images.len(),
+                        report.reclaimed_bytes
+                    ),
+                    Err(e) => tracing::error!("Image garbage collection failed: {e}"),
+                }
+            }
+        });
+    }
 }
@@ -0,0 +1,169 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use eyre::Result;
+use serde::Serialize;
+use sysinfo::{Disks, System};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::docker::DockerManager;
+use crate::ftp::{FtpHandle, FtpHealth};
+
+/// How long the docker daemon gets to answer, so the checks can't hang.
+const DOCKER_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SystemInfo {
+    /// Version of mastiff.
+    pub version: String,
+    /// Name and version of the distribution, eg. `Debian GNU/Linux 12`.
+    pub os: Option<String>,
+    pub kernel: Option<String>,
+    pub architecture: String,
+    pub cpus: usize,
+    pub memory: Capacity,
+    /// The filesystem of the data directory, `None` if it can't be found.
+    pub disk: Option<Capacity>,
+    /// Seconds since the daemon started.
+    pub uptime: u64,
+    pub docker: DockerStatus,
+    pub ftp: FtpHealth,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, ToSchema)]
+pub struct Capacity {
+    /// Bytes in total.
+    pub total: u64,
+    /// Bytes which can still be used. For memory, this includes the caches the
+    /// kernel can drop.
+    pub free: u64,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DockerStatus {
+    pub reachable: bool,
+    pub version: Option<String>,
+    pub api_version: Option<String>,
+}
+
+/// Whether the node can take on work, as answered to load balancers.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    pub ready: bool,
+    pub docker: bool,
+    /// Only false if the FTP server failed, stopping it on purpose doesn't make the
+    /// node unready.
+    pub ftp: bool,
+}
+
+/// Reports on the node itself, for the panel's node monitor and load balancers.
+#[derive(Debug)]
+pub struct SystemManager {
+    docker_manager: Arc<DockerManager>,
+    ftp_handle: Arc<FtpHandle>,
+    data_directory: PathBuf,
+    started: Instant,
+}
+
+impl SystemManager {
+    pub fn new(
+        docker_manager: Arc<DockerManager>,
+        ftp_handle: Arc<FtpHandle>,
+        data_directory: impl Into<PathBuf>,
+    ) -> Self {
+        Self {
+            docker_manager,
+            ftp_handle,
+            data_directory: data_directory.into(),
+            started: Instant::now(),
+        }
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn info(&self) -> Result<SystemInfo> {
+        let data_directory = self.data_directory.clone();
+        // Reading the system information blocks on procfs.
+        let (os, kernel, memory, disk) = tokio::task::spawn_blocking(move || {
+            let mut system = System::new();
+            system.refresh_memory();
+            let memory = Capacity {
+                total: system.total_memory(),
+                free: system.available_memory(),
+            };
+            let os = System::long_os_version();
+            (
+                os,
+                System::kernel_version(),
+                memory,
+                disk_of(&data_directory),
+            )
+        })
+        .await?;
+
+        Ok(SystemInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            os,
+            kernel,
+            architecture: std::env::consts::ARCH.to_string(),
+            cpus: std::thread::available_parallelism().map_or(1, |cpus| cpus.get()),
+            memory,
+            disk,
+            uptime: self.started.elapsed().as_secs(),
+            docker: self.docker_status().await,
+            ftp: self.ftp_handle.health(),
+        })
+    }
+
+    #[instrument(skip(self), level = "debug")]
+    pub async fn readiness(&self) -> Readiness {
+        let docker = self.docker_status().await.reachable;
+        let ftp = !matches!(self.ftp_handle.health(), FtpHealth::Failed { .. });
+        Readiness {
+            ready: docker && ftp,
+            docker,
+            ftp,
+        }
+    }
+
+    async fn docker_status(&self) -> DockerStatus {
+        let version = self.docker_manager.docker().version();
+        let error = match tokio::time::timeout(DOCKER_TIMEOUT, version).await {
+            Ok(Ok(version)) => {
+                return DockerStatus {
+                    reachable: true,
+                    version: version.version,
+                    api_version: version.api_version,
+                }
+            }
+            Ok(Err(e)) => e.to_string(),
+            Err(_) => "timed out".to_string(),
+        };
+
+        tracing::warn!("The docker daemon is unreachable: {error}");
+        DockerStatus {
+            reachable: false,
+            version: None,
+            api_version: None,
+        }
+    }
+}
+
+/// The capacity of the filesystem `path` is on, which is the one mounted at the
+/// longest prefix of the path.
+fn disk_of(path: &Path) -> Option<Capacity> {
+    let path = std::fs::canonicalize(path).ok()?;
+    let disks = Disks::new_with_refreshed_list();
+    disks
+        .list()
+        .iter()
+        .filter(|disk| path.starts_with(disk.mount_point()))
+        .max_by_key(|disk| disk.mount_point().as_os_str().len())
+        .map(|disk| Capacity {
+            total: disk.total_space(),
+            free: disk.available_space(),
+        })
+}
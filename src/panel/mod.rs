@@ -1,6 +1,170 @@
-// TODO: Implement panel methods. Write a document on the entire structure.
-use crate::config::PanelSettings;
+// TODO: Write a document on the entire structure.
+use std::time::Duration;
 
+use eyre::{bail, Result};
+use reqwest::{
+    header::{self, HeaderMap, HeaderValue},
+    Client, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+
+use crate::{
+    config::PanelSettings,
+    ftp::{audit::AuditEvent, permissions::Permissions},
+};
+
+#[derive(Debug)]
 pub struct Panel {
     settings: PanelSettings,
+    client: Client,
+}
+
+#[derive(Debug, Serialize)]
+struct FtpCredentials<'a> {
+    username: &'a str,
+    server_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password: Option<&'a str>,
+    /// Public key in the OpenSSH format, used by SFTP clients.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    public_key: Option<&'a str>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FtpAuthResponse {
+    /// Sub-users only get the permissions the owner granted them. Without any, the
+    /// user can only read.
+    #[serde(default = "Permissions::read_only")]
+    permissions: Permissions,
+}
+
+/// The outcome of verifying credentials with the panel.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CredentialCheck {
+    Valid(Permissions),
+    BadPassword,
+    UnknownUser,
+}
+
+impl Panel {
+    /// The requests are authenticated with the node token, as a bearer token.
+    pub fn new(settings: PanelSettings, token: &str) -> Self {
+        let mut authorization = HeaderValue::from_str(&format!("Bearer {token}"))
+            .expect("The node token is a valid header value");
+        authorization.set_sensitive(true);
+
+        // Logins wait on the panel, so it can't be allowed to hang.
+        let client = Client::builder()
+            .default_headers(HeaderMap::from_iter([(
+                header::AUTHORIZATION,
+                authorization,
+            )]))
+            .connect_timeout(Duration::from_secs(settings.connect_timeout))
+            .timeout(Duration::from_secs(settings.request_timeout))
+            .build()
+            .expect("Could not build the panel client");
+
+        Self { settings, client }
+    }
+
+    /// Asks the panel whether the user can access the server's files with the password.
+    #[instrument(skip(self, password), level = "debug")]
+    pub async fn verify_ftp_credentials(
+        &self,
+        username: &str,
+        server_id: &str,
+        password: &str,
+    ) -> Result<CredentialCheck> {
+        self.check_ftp_credentials(&FtpCredentials {
+            username,
+            server_id,
+            password: Some(password),
+            public_key: None,
+        })
+        .await
+    }
+
+    /// Asks the panel whether the user can access the server's files with the public key.
+    #[instrument(skip(self), level = "debug")]
+    pub async fn verify_ftp_public_key(
+        &self,
+        username: &str,
+        server_id: &str,
+        public_key: &str,
+    ) -> Result<CredentialCheck> {
+        self.check_ftp_credentials(&FtpCredentials {
+            username,
+            server_id,
+            password: None,
+            public_key: Some(public_key),
+        })
+        .await
+    }
+
+    async fn check_ftp_credentials(
+        &self,
+        credentials: &FtpCredentials<'_>,
+    ) -> Result<CredentialCheck> {
+        let response = self.post("api/node/ftp/auth", credentials).await?;
+
+        Ok(match response.status() {
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => CredentialCheck::BadPassword,
+            StatusCode::NOT_FOUND => CredentialCheck::UnknownUser,
+            status if status.is_success() => {
+                // Older panels answer without a body, which only grants read access.
+                let body = response.bytes().await?;
+                let permissions = if body.is_empty() {
+                    Permissions::read_only()
+                } else {
+                    serde_json::from_slice::<FtpAuthResponse>(&body)?.permissions
+                };
+                CredentialCheck::Valid(permissions)
+            }
+            status => bail!("Panel responded with {status} to ftp authentication"),
+        })
+    }
+
+    /// Forwards a batch of ftp audit events to the panel.
+    #[instrument(skip_all, level = "debug")]
+    pub async fn send_ftp_audit_events(&self, events: &[AuditEvent]) -> Result<()> {
+        let response = self.post("api/node/ftp/audit", events).await?;
+        if !response.status().is_success() {
+            bail!(
+                "Panel responded with {} to ftp audit events",
+                response.status()
+            );
+        }
+        Ok(())
+    }
+
+    /// Sends a request to the panel, retrying on connection failures and server errors.
+    async fn post<T: Serialize + ?Sized>(&self, path: &str, body: &T) -> Result<Response> {
+        let url = self.settings.url.join(path)?;
+        let mut attempt = 0;
+
+        loop {
+            let response = self.client.post(url.clone()).json(body).send().await;
+
+            match response {
+                Ok(response) if !response.status().is_server_error() => return Ok(response),
+                _ if attempt >= self.settings.max_retries => {
+                    let error = match response {
+                        Ok(response) => response.status().to_string(),
+                        Err(e) => e.to_string(),
+                    };
+                    bail!("Request to the panel at {url} failed: {error}");
+                }
+                _ => {}
+            }
+
+            // Back off exponentially, up to the configured limit.
+            let wait = 2u64
+                .saturating_pow(attempt.into())
+                .min(self.settings.max_timeout.into());
+            tracing::debug!("Retrying request to {url} in {wait} seconds");
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+            attempt += 1;
+        }
+    }
 }
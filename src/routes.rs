@@ -1,38 +1,120 @@
 use std::sync::Arc;
 
-use axum::{
-    http::StatusCode,
-    response::{IntoResponse, Response},
-    routing::{delete, post},
-    Router,
-};
+use axum::{extract::DefaultBodyLimit, middleware, Router};
+use utoipa::OpenApi;
+use utoipa_axum::{router::OpenApiRouter, routes};
 
-use crate::managers::{recipe::RecipeManager, Managers};
+use crate::{
+    config::ApiSettings,
+    managers::{recipe::RecipeManager, Managers},
+};
 
+pub mod admin;
+pub mod auth;
+pub mod error;
+pub mod extract;
+pub mod files;
+pub mod ftp;
+pub mod image;
+pub mod metrics;
+pub mod network;
+pub mod openapi;
 pub mod recipe;
+pub mod server;
+pub mod system;
 
-// TODO: Implement concrete error types.
-pub struct AppError(eyre::Error);
+pub use error::AppError;
 
-impl IntoResponse for AppError {
-    fn into_response(self) -> Response {
-        (StatusCode::INTERNAL_SERVER_ERROR, self.0.to_string()).into_response()
-    }
-}
+pub fn initialise_routes(managers: Managers, settings: &ApiSettings) -> Router {
+    let (router, document) = api_routes(settings).split_for_parts();
 
-impl<E> From<E> for AppError
-where
-    E: Into<eyre::Error>,
-{
-    fn from(err: E) -> Self {
-        Self(err.into())
-    }
+    router
+        .merge(openapi::routes(document, settings.docs_bundle.clone()))
+        .route_layer(middleware::from_fn(metrics::track_requests))
+        // Outermost, so the rejected requests get an id too.
+        .layer(middleware::from_fn(error::request_id))
+        .with_state(managers)
 }
 
-pub fn initialise_routes(managers: Managers) -> Router {
-    let recipe_routes = Router::new()
-        .route("/recipes/upload", post(recipe::upload_recipe))
-        .route("/recipes/:name", delete(recipe::delete_recipe));
+/// The routes of the API, along with the OpenAPI document describing them.
+fn api_routes(settings: &ApiSettings) -> OpenApiRouter<Managers> {
+    let recipe_routes = OpenApiRouter::new()
+        .routes(routes!(recipe::upload_recipe))
+        // Only applies to the upload, the routes below are added after it.
+        .layer(DefaultBodyLimit::max(settings.max_recipe_size))
+        .routes(routes!(recipe::delete_recipe))
+        .routes(routes!(recipe::rollback_recipe));
+
+    let image_routes = OpenApiRouter::new().routes(routes!(image::collect_garbage));
+
+    let network_routes = OpenApiRouter::new()
+        .routes(routes!(network::link_servers, network::unlink_servers))
+        .routes(routes!(
+            network::get_egress_policy,
+            network::set_egress_policy
+        ));
+
+    let server_routes = OpenApiRouter::new().routes(routes!(server::set_disk_limit));
+
+    // The routes users of the panel can reach, with a token scoped to `stats`,
+    // `files` or `ftp` of their server.
+    let user_routes = OpenApiRouter::new()
+        .routes(routes!(server::get_stats))
+        .routes(routes!(ftp::get_audit_log))
+        .routes(routes!(files::list_directory))
+        .routes(routes!(files::read_file, files::write_file))
+        .routes(routes!(files::create_directory))
+        .routes(routes!(files::rename_file))
+        .routes(routes!(files::copy_file))
+        .routes(routes!(files::delete_files))
+        .routes(routes!(files::chmod))
+        .routes(routes!(files::compress))
+        .routes(routes!(files::decompress))
+        .routes(routes!(files::pull))
+        .routes(routes!(files::list_jobs))
+        .routes(routes!(files::get_job))
+        .routes(routes!(files::create_upload))
+        .routes(routes!(
+            files::get_upload,
+            files::append_upload,
+            files::cancel_upload
+        ))
+        .routes(routes!(files::finish_upload))
+        .routes(routes!(files::create_download_link));
+
+    let system_routes = OpenApiRouter::new()
+        .routes(routes!(system::get_system))
+        .routes(routes!(metrics::get_metrics))
+        .routes(routes!(admin::get_log_filter, admin::set_log_filter));
+
+    // Authenticated by their own means or meant for load balancers, so they are
+    // added after the auth layer.
+    let public_routes = OpenApiRouter::new()
+        .routes(routes!(files::download))
+        .routes(routes!(system::health))
+        .routes(routes!(system::ready));
+
+    let ftp_routes = OpenApiRouter::new()
+        .routes(routes!(ftp::get_health))
+        .routes(routes!(ftp::start))
+        .routes(routes!(ftp::stop))
+        .routes(routes!(ftp::restart));
+
+    let node_routes = OpenApiRouter::new()
+        .merge(recipe_routes)
+        .merge(image_routes)
+        .merge(network_routes)
+        .merge(server_routes)
+        .merge(ftp_routes)
+        .merge(system_routes)
+        .layer(middleware::from_fn(auth::node_only));
 
-    Router::new().merge(recipe_routes).with_state(managers)
+    OpenApiRouter::with_openapi(openapi::ApiDoc::openapi())
+        .merge(node_routes)
+        .merge(user_routes)
+        .layer(middleware::from_fn_with_state(
+            Arc::new(auth::ApiAuth::new(settings)),
+            auth::authenticate,
+        ))
+        .merge(public_routes)
 }
@@ -0,0 +1,51 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::{error::ErrorBody, extract::Json, AppError};
+use crate::logging::LogFilter;
+
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct LogFilterBody {
+    /// Directives in the syntax of `RUST_LOG`, eg. `info,mastiff_backend::ftp=debug`.
+    filter: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/admin/log-filter",
+    tag = "system",
+    responses((status = 200, body = LogFilterBody)),
+)]
+#[instrument(skip(log_filter), level = "debug")]
+pub async fn get_log_filter(
+    State(log_filter): State<Arc<LogFilter>>,
+) -> Result<Json<LogFilterBody>, AppError> {
+    Ok(Json(LogFilterBody {
+        filter: log_filter.get()?,
+    }))
+}
+
+/// Changes which events are logged until the daemon restarts, eg. to debug a
+/// module without restarting.
+#[utoipa::path(
+    put,
+    path = "/admin/log-filter",
+    tag = "system",
+    request_body = LogFilterBody,
+    responses(
+        (status = 204, description = "The filter is in effect"),
+        (status = 400, description = "The filter is invalid", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(log_filter), level = "debug")]
+pub async fn set_log_filter(
+    State(log_filter): State<Arc<LogFilter>>,
+    Json(body): Json<LogFilterBody>,
+) -> Result<StatusCode, AppError> {
+    log_filter.set(&body.filter)?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,349 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    body::{self, Body},
+    extract::{Request, State},
+    http::{header, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use jsonwebtoken::{Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::AppError;
+use crate::config::ApiSettings;
+
+const TIMESTAMP_HEADER: &str = "x-mastiff-timestamp";
+const NONCE_HEADER: &str = "x-mastiff-nonce";
+const SIGNATURE_HEADER: &str = "x-mastiff-signature";
+
+/// Largest body accepted in signed requests, which are buffered to be verified.
+/// Larger uploads have to use the bearer token.
+const MAX_SIGNED_BODY: usize = 4 * 1024 * 1024;
+
+/// Claims of the tokens the panel issues to its users.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserClaims {
+    /// The panel's id of the user.
+    pub sub: String,
+    /// The only server the token grants access to.
+    pub server_id: String,
+    /// The parts of the server which can be accessed, eg. `console` grants access
+    /// to `/servers/<id>/console` and everything below it.
+    pub scopes: Vec<String>,
+    pub exp: u64,
+}
+
+impl UserClaims {
+    fn allows(&self, path: &str) -> bool {
+        let mut segments = path.trim_start_matches('/').split('/');
+        segments.next() == Some("servers")
+            && segments.next() == Some(self.server_id.as_str())
+            && segments
+                .next()
+                .is_some_and(|scope| self.scopes.iter().any(|allowed| allowed == scope))
+    }
+}
+
+/// Who made a request, added to the extensions of authenticated requests.
+#[derive(Debug, Clone)]
+pub enum Access {
+    /// The panel, with the node token or a signature made with it.
+    Node,
+    /// A user of the panel, limited to the scopes in the token.
+    User(UserClaims),
+}
+
+/// Verifies the requests made to the API. The panel authenticates with the node
+/// token, either as a bearer token or by signing the request with it. Users of the
+/// panel authenticate with short-lived JWTs the panel signs with `jwt_secret`.
+#[derive(Debug)]
+pub struct ApiAuth {
+    token: String,
+    jwt_secret: String,
+    signature_max_age: u64,
+    /// Nonces of the signed requests which are still within `signature_max_age`.
+    nonces: Mutex<HashMap<String, Instant>>,
+}
+
+impl ApiAuth {
+    pub fn new(settings: &ApiSettings) -> Self {
+        Self {
+            token: settings.token.clone(),
+            jwt_secret: settings.jwt_secret.clone(),
+            signature_max_age: settings.signature_max_age,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn verify(&self, request: Request) -> Result<(Access, Request), &'static str> {
+        if request.headers().contains_key(SIGNATURE_HEADER) {
+            let request = self.verify_signature(request).await?;
+            return Ok((Access::Node, request));
+        }
+
+        let token = bearer_token(request.headers()).ok_or("Missing credentials")?;
+        if constant_time_eq(token.as_bytes(), self.token.as_bytes()) {
+            return Ok((Access::Node, request));
+        }
+
+        let claims = jsonwebtoken::decode::<UserClaims>(
+            token,
+            &DecodingKey::from_secret(self.jwt_secret.as_bytes()),
+            &Validation::new(Algorithm::HS256),
+        )
+        .map_err(|_| "Invalid token")?
+        .claims;
+
+        if !claims.allows(request.uri().path()) {
+            return Err("The token does not grant access to this resource");
+        }
+        Ok((Access::User(claims), request))
+    }
+
+    /// Checks the HMAC-SHA256 signature, made with the node token, of
+    /// `<timestamp>\n<nonce>\n<method>\n<path and query>\n<hex sha256 of the body>`.
+    async fn verify_signature(&self, request: Request) -> Result<Request, &'static str> {
+        let (parts, body) = request.into_parts();
+
+        let timestamp: u64 = header_str(&parts.headers, TIMESTAMP_HEADER)
+            .and_then(|timestamp| timestamp.parse().ok())
+            .ok_or("Invalid timestamp")?;
+        let nonce = header_str(&parts.headers, NONCE_HEADER).ok_or("Missing nonce")?;
+        let signature = header_str(&parts.headers, SIGNATURE_HEADER)
+            .and_then(|signature| hex::decode(signature).ok())
+            .ok_or("Invalid signature")?;
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now.abs_diff(timestamp) > self.signature_max_age {
+            return Err("The signature has expired");
+        }
+
+        let body = body::to_bytes(body, MAX_SIGNED_BODY)
+            .await
+            .map_err(|_| "The request body is too large to be signed")?;
+        let path = parts
+            .uri
+            .path_and_query()
+            .map_or(parts.uri.path(), |path| path.as_str());
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(self.token.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(format!("{timestamp}\n{nonce}\n{}\n{path}\n", parts.method).as_bytes());
+        mac.update(hex::encode(Sha256::digest(&body)).as_bytes());
+        mac.verify_slice(&signature)
+            .map_err(|_| "Invalid signature")?;
+
+        // Only valid requests are remembered, so the cache can't be flooded.
+        self.remember_nonce(nonce)?;
+        Ok(Request::from_parts(parts, Body::from(body)))
+    }
+
+    fn remember_nonce(&self, nonce: &str) -> Result<(), &'static str> {
+        let now = Instant::now();
+        // Requests are accepted from this far in the past and in the future.
+        let window = Duration::from_secs(self.signature_max_age * 2);
+        let mut nonces = self.nonces.lock().unwrap();
+
+        nonces.retain(|_, seen| now.duration_since(*seen) < window);
+        if nonces.contains_key(nonce) {
+            return Err("The request has already been made");
+        }
+        nonces.insert(nonce.to_string(), now);
+        Ok(())
+    }
+}
+
+/// Rejects the requests which aren't authenticated.
+pub async fn authenticate(
+    State(auth): State<Arc<ApiAuth>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match auth.verify(request).await {
+        Ok((access, mut request)) => {
+            request.extensions_mut().insert(access);
+            next.run(request).await
+        }
+        Err(reason) => {
+            tracing::debug!("Rejected unauthenticated request: {reason}");
+            AppError::Unauthorized(reason.to_string()).into_response()
+        }
+    }
+}
+
+/// Refuses the users of the panel, for the routes which manage the node or change
+/// what a server is allowed to do, whatever scopes their token has.
+pub async fn node_only(request: Request, next: Next) -> Response {
+    match request.extensions().get::<Access>() {
+        Some(Access::Node) => next.run(request).await,
+        _ => AppError::Forbidden("Only the panel can access this resource".to_string())
+            .into_response(),
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name)?.to_str().ok()
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    header_str(headers, header::AUTHORIZATION.as_str())?.strip_prefix("Bearer ")
+}
+
+/// Compares without returning early, so the time taken doesn't leak the token.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http;
+    use jsonwebtoken::{EncodingKey, Header};
+    use serde_json::json;
+
+    use super::*;
+
+    const TOKEN: &str = "node-token";
+    const JWT_SECRET: &str = "jwt-secret";
+
+    fn auth() -> ApiAuth {
+        ApiAuth {
+            token: TOKEN.to_string(),
+            jwt_secret: JWT_SECRET.to_string(),
+            signature_max_age: 30,
+            nonces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    fn sign(timestamp: u64, nonce: &str, method: &str, path: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(TOKEN.as_bytes()).unwrap();
+        mac.update(format!("{timestamp}\n{nonce}\n{method}\n{path}\n").as_bytes());
+        mac.update(hex::encode(Sha256::digest(body)).as_bytes());
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn signed_request(timestamp: u64, nonce: &str, signature: &str, body: Vec<u8>) -> Request {
+        http::Request::post("/servers/1/files/write")
+            .header(TIMESTAMP_HEADER, timestamp.to_string())
+            .header(NONCE_HEADER, nonce)
+            .header(SIGNATURE_HEADER, signature)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn user_request(path: &str, server_id: &str, scopes: &[&str]) -> Request {
+        let claims = json!({
+            "sub": "user",
+            "server_id": server_id,
+            "scopes": scopes,
+            "exp": now() + 60,
+        });
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(JWT_SECRET.as_bytes()),
+        )
+        .unwrap();
+        http::Request::get(path)
+            .header(header::AUTHORIZATION, format!("Bearer {token}"))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn valid_signature_is_accepted() {
+        let timestamp = now();
+        let signature = sign(timestamp, "a", "POST", "/servers/1/files/write", b"data");
+        let request = signed_request(timestamp, "a", &signature, b"data".to_vec());
+
+        let (access, request) = auth().verify(request).await.unwrap();
+        assert!(matches!(access, Access::Node));
+        // The buffered body is passed on to the handler.
+        let body = body::to_bytes(request.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(&body[..], b"data");
+    }
+
+    #[tokio::test]
+    async fn tampered_body_is_rejected() {
+        let timestamp = now();
+        let signature = sign(timestamp, "a", "POST", "/servers/1/files/write", b"data");
+        let request = signed_request(timestamp, "a", &signature, b"other".to_vec());
+
+        assert!(auth().verify(request).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stale_timestamp_is_rejected() {
+        let timestamp = now() - 60;
+        let signature = sign(timestamp, "a", "POST", "/servers/1/files/write", b"");
+        let request = signed_request(timestamp, "a", &signature, Vec::new());
+
+        assert_eq!(
+            auth().verify(request).await.unwrap_err(),
+            "The signature has expired"
+        );
+    }
+
+    #[tokio::test]
+    async fn replayed_nonce_is_rejected() {
+        let auth = auth();
+        let timestamp = now();
+        let signature = sign(timestamp, "a", "POST", "/servers/1/files/write", b"");
+
+        let request = signed_request(timestamp, "a", &signature, Vec::new());
+        assert!(auth.verify(request).await.is_ok());
+        let request = signed_request(timestamp, "a", &signature, Vec::new());
+        assert_eq!(
+            auth.verify(request).await.unwrap_err(),
+            "The request has already been made"
+        );
+    }
+
+    #[tokio::test]
+    async fn large_signed_bodies_are_rejected() {
+        let body = vec![0; MAX_SIGNED_BODY + 1];
+        let timestamp = now();
+        let signature = sign(timestamp, "a", "POST", "/servers/1/files/write", &body);
+        let request = signed_request(timestamp, "a", &signature, body);
+
+        assert_eq!(
+            auth().verify(request).await.unwrap_err(),
+            "The request body is too large to be signed"
+        );
+    }
+
+    #[tokio::test]
+    async fn user_tokens_are_limited_to_their_scopes() {
+        let auth = auth();
+
+        let request = user_request("/servers/1/files/list", "1", &["files"]);
+        let (access, _) = auth.verify(request).await.unwrap();
+        assert!(matches!(access, Access::User(claims) if claims.server_id == "1"));
+
+        // Another server.
+        let request = user_request("/servers/2/files/list", "1", &["files"]);
+        assert!(auth.verify(request).await.is_err());
+
+        // A scope the token doesn't have.
+        let request = user_request("/servers/1/stats", "1", &["files"]);
+        assert!(auth.verify(request).await.is_err());
+    }
+}
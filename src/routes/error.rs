@@ -0,0 +1,253 @@
+use axum::{
+    extract::{
+        rejection::{JsonRejection, PathRejection, QueryRejection},
+        Request,
+    },
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use crate::{
+    logging::LogFilterError,
+    managers::{
+        docker::ImageError,
+        files::{
+            archive::ArchiveError, download::DownloadError, job::JobNotFound, pull::PullError,
+            upload::UploadError, FileError,
+        },
+        network::NetworkError,
+        quota::QuotaError,
+        recipe::RecipeError,
+    },
+};
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+tokio::task_local! {
+    /// The id of the request being handled, included in the error bodies.
+    static REQUEST_ID: String;
+}
+
+/// An error returned by the API. The errors of the managers are mapped to a status
+/// code and a stable, machine-readable code when they are converted.
+///
+/// It doesn't implement [`std::error::Error`], otherwise it would conflict with the
+/// blanket `From` implementation.
+#[derive(Debug)]
+pub enum AppError {
+    RecipeNotFound(String),
+    InvalidArchive(String),
+    /// The docker daemon can't be reached.
+    DockerUnavailable(String),
+    NotFound(String),
+    /// The request conflicts with the state of the resource.
+    Conflict(String),
+    QuotaExceeded(String),
+    Unauthorized(String),
+    /// Authenticated, but not allowed to access the resource.
+    Forbidden(String),
+    BadRequest(String),
+    Internal(eyre::Error),
+}
+
+/// The body of every error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorBody {
+    /// Stable, machine-readable code, eg. `not_found`.
+    #[schema(value_type = String)]
+    code: &'static str,
+    message: String,
+    request_id: Option<String>,
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::RecipeNotFound(_) | AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::InvalidArchive(_) | AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::DockerUnavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::QuotaExceeded(_) => StatusCode::INSUFFICIENT_STORAGE,
+            AppError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden(_) => StatusCode::FORBIDDEN,
+            AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// The code clients can match on, it doesn't change between versions.
+    pub fn code(&self) -> &'static str {
+        match self {
+            AppError::RecipeNotFound(_) => "recipe_not_found",
+            AppError::InvalidArchive(_) => "invalid_archive",
+            AppError::DockerUnavailable(_) => "docker_unavailable",
+            AppError::NotFound(_) => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::QuotaExceeded(_) => "quota_exceeded",
+            AppError::Unauthorized(_) => "unauthorized",
+            AppError::Forbidden(_) => "forbidden",
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Internal(_) => "internal",
+        }
+    }
+
+    fn message(&self) -> String {
+        match self {
+            AppError::RecipeNotFound(message)
+            | AppError::InvalidArchive(message)
+            | AppError::DockerUnavailable(message)
+            | AppError::NotFound(message)
+            | AppError::Conflict(message)
+            | AppError::QuotaExceeded(message)
+            | AppError::Unauthorized(message)
+            | AppError::Forbidden(message)
+            | AppError::BadRequest(message) => message.clone(),
+            // The details are only logged, they can contain paths or addresses.
+            AppError::Internal(_) => {
+                "Internal server error, the request id identifies it in the logs".to_string()
+            }
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let request_id = REQUEST_ID.try_with(Clone::clone).ok();
+        if let AppError::Internal(e) = &self {
+            tracing::error!(request_id = request_id.as_deref(), "Request failed: {e:?}");
+        }
+
+        let body = ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            request_id,
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+impl<E> From<E> for AppError
+where
+    E: Into<eyre::Error>,
+{
+    fn from(err: E) -> Self {
+        let err: eyre::Error = err.into();
+
+        if let Some(e) = err.downcast_ref::<JsonRejection>() {
+            return AppError::BadRequest(e.body_text());
+        }
+        if let Some(e) = err.downcast_ref::<PathRejection>() {
+            return AppError::BadRequest(e.body_text());
+        }
+        if let Some(e) = err.downcast_ref::<QueryRejection>() {
+            return AppError::BadRequest(e.body_text());
+        }
+        if let Some(e) = err.downcast_ref::<RecipeError>() {
+            return match e {
+                RecipeError::NotFound(_) => AppError::RecipeNotFound(e.to_string()),
+                RecipeError::InvalidArchive(_) | RecipeError::InvalidRecipe(_) => {
+                    AppError::InvalidArchive(e.to_string())
+                }
+                RecipeError::InvalidName(_) => AppError::BadRequest(e.to_string()),
+            };
+        }
+        if let Some(e) = err.downcast_ref::<ImageError>() {
+            return AppError::Conflict(e.to_string());
+        }
+        if let Some(e) = err.downcast_ref::<NetworkError>() {
+            return match e {
+                NetworkError::ServerNotFound(_) => AppError::NotFound(e.to_string()),
+                NetworkError::DifferentOwners => AppError::Conflict(e.to_string()),
+                NetworkError::InvalidPolicy(_) => AppError::BadRequest(e.to_string()),
+            };
+        }
+        if let Some(e) = err.downcast_ref::<FileError>() {
+            return match e {
+                FileError::NotFound(_) => AppError::NotFound(e.to_string()),
+                FileError::AlreadyExists(_) => AppError::Conflict(e.to_string()),
+                FileError::OutsideJail(_) => AppError::Forbidden(e.to_string()),
+                FileError::InvalidServerId(_) | FileError::InvalidPath { .. } => {
+                    AppError::BadRequest(e.to_string())
+                }
+            };
+        }
+        if let Some(e) = err.downcast_ref::<ArchiveError>() {
+            return match e {
+                ArchiveError::UnsupportedFormat(_) => AppError::BadRequest(e.to_string()),
+                ArchiveError::TooLarge(_)
+                | ArchiveError::TooManyEntries(_)
+                | ArchiveError::UnsafePath(_) => AppError::InvalidArchive(e.to_string()),
+            };
+        }
+        if let Some(e) = err.downcast_ref::<UploadError>() {
+            return match e {
+                UploadError::NotFound(_) => AppError::NotFound(e.to_string()),
+                UploadError::Busy(_)
+                | UploadError::OffsetMismatch { .. }
+                | UploadError::Incomplete { .. } => AppError::Conflict(e.to_string()),
+                UploadError::TooLarge { .. } | UploadError::SizeLimit(_) => {
+                    AppError::BadRequest(e.to_string())
+                }
+            };
+        }
+        if let Some(e) = err.downcast_ref::<DownloadError>() {
+            return AppError::Unauthorized(e.to_string());
+        }
+        if let Some(e) = err.downcast_ref::<PullError>() {
+            return match e {
+                PullError::ForbiddenAddress(_) => AppError::Forbidden(e.to_string()),
+                _ => AppError::BadRequest(e.to_string()),
+            };
+        }
+        if let Some(e) = err.downcast_ref::<JobNotFound>() {
+            return AppError::NotFound(e.to_string());
+        }
+        if let Some(e) = err.downcast_ref::<QuotaError>() {
+            return AppError::QuotaExceeded(e.to_string());
+        }
+        if let Some(e) = err.downcast_ref::<LogFilterError>() {
+            return AppError::BadRequest(e.to_string());
+        }
+        if let Some(e) = err.downcast_ref::<docker_api::Error>() {
+            // docker-api uses its own version of `http`, so only the numbers compare.
+            if let docker_api::Error::Fault { code, message } = e {
+                match code.as_u16() {
+                    404 => return AppError::NotFound(message.clone()),
+                    409 => return AppError::Conflict(message.clone()),
+                    _ => {}
+                }
+            }
+            // Failing to connect to the socket shows up as an IO error somewhere
+            // down the chain.
+            if err.chain().any(|cause| cause.is::<std::io::Error>()) {
+                return AppError::DockerUnavailable(format!("Could not reach docker: {e}"));
+            }
+        }
+
+        AppError::Internal(err)
+    }
+}
+
+/// Tags every request with an id, taken from the `X-Request-Id` header if the
+/// client sent one, and returns it in the same header.
+pub async fn request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let header = HeaderValue::from_str(&id).expect("request ids are valid header values");
+    request
+        .headers_mut()
+        .insert(REQUEST_ID_HEADER, header.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(request)).await;
+    response.headers_mut().insert(REQUEST_ID_HEADER, header);
+    response
+}
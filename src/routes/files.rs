@@ -0,0 +1,655 @@
+use std::{io, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header, HeaderMap, StatusCode},
+    response::IntoResponse,
+};
+use serde::{Deserialize, Serialize};
+use tokio_stream::StreamExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
+
+use super::{
+    error::ErrorBody,
+    extract::{Json, Path, Query},
+    AppError,
+};
+use crate::managers::files::{job::JobStatus, upload::UploadStatus, FileEntry, FileManager};
+
+/// Offset of the chunk in the upload, like in the tus protocol.
+const UPLOAD_OFFSET_HEADER: &str = "upload-offset";
+const UPLOAD_LENGTH_HEADER: &str = "upload-length";
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct PathQuery {
+    /// Path relative to the server directory, the server directory itself if empty.
+    #[serde(default)]
+    path: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateDirectory {
+    path: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct MoveFile {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DeleteFiles {
+    paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Chmod {
+    path: String,
+    /// Permission bits in octal, eg. `755`.
+    mode: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Compress {
+    paths: Vec<String>,
+    /// Path of the new archive, its extension selects the format.
+    destination: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Decompress {
+    path: String,
+    /// Directory to extract into, the archive's directory if not given.
+    destination: Option<String>,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct Pull {
+    url: String,
+    /// Path of the new file.
+    path: String,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateUpload {
+    path: String,
+    /// Size of the whole file in bytes.
+    size: u64,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct CreateDownloadLink {
+    path: String,
+    /// No of seconds the link is valid for, the configured maximum if not given.
+    expires_in: Option<u64>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DownloadLink {
+    /// Path and query of the link, relative to the address of the node.
+    url: String,
+    expires: u64,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DownloadQuery {
+    token: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/files/list",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        PathQuery,
+    ),
+    responses(
+        (status = 200, body = Vec<FileEntry>),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn list_directory(
+    Path(server_id): Path<String>,
+    Query(query): Query<PathQuery>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<Json<Vec<FileEntry>>, AppError> {
+    Ok(Json(file_manager.list(&server_id, &query.path).await?))
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/files/contents",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        PathQuery,
+    ),
+    responses(
+        (
+            status = 200, description = "The contents of the file",
+            body = Vec<u8>, content_type = "application/octet-stream"
+        ),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn read_file(
+    Path(server_id): Path<String>,
+    Query(query): Query<PathQuery>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (file, size) = file_manager.read_file(&server_id, &query.path).await?;
+    let mime = mime_guess::from_path(&query.path).first_or_octet_stream();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ))
+}
+
+/// Streams the body into the file, so large files aren't buffered.
+#[utoipa::path(
+    put,
+    path = "/servers/{id}/files/contents",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        PathQuery,
+    ),
+    request_body(content = Vec<u8>, content_type = "application/octet-stream"),
+    responses(
+        (status = 204, description = "The file was written"),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager, body), level = "debug")]
+pub async fn write_file(
+    Path(server_id): Path<String>,
+    Query(query): Query<PathQuery>,
+    State(file_manager): State<Arc<FileManager>>,
+    body: Body,
+) -> Result<StatusCode, AppError> {
+    let stream = body
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(io::Error::other));
+    file_manager
+        .write_file(&server_id, &query.path, StreamReader::new(stream))
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/directory",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = CreateDirectory,
+    responses(
+        (status = 201, description = "The directory was created"),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 409, description = "The destination already exists", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn create_directory(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<CreateDirectory>,
+) -> Result<StatusCode, AppError> {
+    file_manager.create_dir(&server_id, &request.path).await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/rename",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = MoveFile,
+    responses(
+        (status = 204, description = "The file was moved"),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+        (status = 409, description = "The destination already exists", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn rename_file(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<MoveFile>,
+) -> Result<StatusCode, AppError> {
+    file_manager
+        .rename(&server_id, &request.from, &request.to)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/copy",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = MoveFile,
+    responses(
+        (status = 201, description = "The file was copied"),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+        (status = 409, description = "The destination already exists", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn copy_file(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<MoveFile>,
+) -> Result<StatusCode, AppError> {
+    file_manager
+        .copy(&server_id, &request.from, &request.to)
+        .await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/delete",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = DeleteFiles,
+    responses(
+        (status = 204, description = "The files were deleted"),
+        (status = 400, description = "The path is invalid", body = ErrorBody),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn delete_files(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<DeleteFiles>,
+) -> Result<StatusCode, AppError> {
+    file_manager.delete(&server_id, &request.paths).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/chmod",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = Chmod,
+    responses(
+        (status = 204, description = "The permissions were changed"),
+        (status = 400, description = "The mode is invalid", body = ErrorBody),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn chmod(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<Chmod>,
+) -> Result<StatusCode, AppError> {
+    let mode = u32::from_str_radix(&request.mode, 8)
+        .ok()
+        // setuid, setgid and sticky bits can't be set.
+        .filter(|mode| *mode <= 0o777)
+        .ok_or_else(|| AppError::BadRequest(format!("Invalid mode: {}", request.mode)))?;
+
+    file_manager.chmod(&server_id, &request.path, mode).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/compress",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = Compress,
+    responses(
+        (status = 202, description = "The archive is being written", body = JobStatus),
+        (status = 400, description = "The format is not supported", body = ErrorBody),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+        (status = 409, description = "The destination already exists", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn compress(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<Compress>,
+) -> Result<(StatusCode, Json<JobStatus>), AppError> {
+    let job = file_manager
+        .compress(&server_id, &request.paths, &request.destination)
+        .await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/decompress",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = Decompress,
+    responses(
+        (status = 202, description = "The archive is being extracted", body = JobStatus),
+        (status = 400, description = "The format is not supported", body = ErrorBody),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn decompress(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<Decompress>,
+) -> Result<(StatusCode, Json<JobStatus>), AppError> {
+    let job = file_manager
+        .decompress(&server_id, &request.path, request.destination.as_deref())
+        .await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/pull",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = Pull,
+    responses(
+        (status = 202, description = "The file is being downloaded", body = JobStatus),
+        (status = 400, description = "The url is invalid", body = ErrorBody),
+        (status = 403, description = "The url or the path is not allowed", body = ErrorBody),
+        (status = 409, description = "The destination already exists", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn pull(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<Pull>,
+) -> Result<(StatusCode, Json<JobStatus>), AppError> {
+    let job = file_manager
+        .pull(&server_id, &request.url, &request.path)
+        .await?;
+    Ok((StatusCode::ACCEPTED, Json(job)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/files/jobs",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    responses(
+        (status = 200, body = Vec<JobStatus>),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn list_jobs(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Json<Vec<JobStatus>> {
+    Json(file_manager.jobs(&server_id))
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/files/jobs/{job}",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("job" = String, Path, description = "Id of the job"),
+    ),
+    responses(
+        (status = 200, body = JobStatus),
+        (status = 404, description = "The job doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn get_job(
+    Path((server_id, job_id)): Path<(String, String)>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<Json<JobStatus>, AppError> {
+    Ok(Json(file_manager.job(&server_id, &job_id)?))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/uploads",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = CreateUpload,
+    responses(
+        (status = 201, body = UploadStatus),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 507, description = "The server is over its disk limit", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn create_upload(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<CreateUpload>,
+) -> Result<(StatusCode, Json<UploadStatus>), AppError> {
+    let upload = file_manager
+        .create_upload(&server_id, &request.path, request.size)
+        .await?;
+    Ok((StatusCode::CREATED, Json(upload)))
+}
+
+/// Also answers `HEAD`, with the offset and size in the `Upload-Offset` and
+/// `Upload-Length` headers.
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/files/uploads/{upload}",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("upload" = String, Path, description = "Id of the upload"),
+    ),
+    responses(
+        (
+            status = 200, body = UploadStatus,
+            headers(("upload-offset" = u64), ("upload-length" = u64))
+        ),
+        (status = 404, description = "The upload doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn get_upload(
+    Path((server_id, upload_id)): Path<(String, String)>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<impl IntoResponse, AppError> {
+    let upload = file_manager.upload_status(&server_id, &upload_id).await?;
+    Ok((upload_headers(&upload), Json(upload)))
+}
+
+/// Appends the body to the upload, at the offset in the `Upload-Offset` header.
+#[utoipa::path(
+    patch,
+    path = "/servers/{id}/files/uploads/{upload}",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("upload" = String, Path, description = "Id of the upload"),
+        ("upload-offset" = u64, Header, description = "Offset of the chunk in the file"),
+    ),
+    request_body(content = Vec<u8>, content_type = "application/offset+octet-stream"),
+    responses(
+        (
+            status = 204, description = "The chunk was written",
+            headers(("upload-offset" = u64), ("upload-length" = u64))
+        ),
+        (status = 400, description = "The chunk is larger than the rest", body = ErrorBody),
+        (status = 404, description = "The upload doesn't exist", body = ErrorBody),
+        (status = 409, description = "The offset is wrong or the upload busy", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager, headers, body), level = "debug")]
+pub async fn append_upload(
+    Path((server_id, upload_id)): Path<(String, String)>,
+    State(file_manager): State<Arc<FileManager>>,
+    headers: HeaderMap,
+    body: Body,
+) -> Result<impl IntoResponse, AppError> {
+    let offset = headers
+        .get(UPLOAD_OFFSET_HEADER)
+        .and_then(|offset| offset.to_str().ok())
+        .and_then(|offset| offset.parse().ok())
+        .ok_or_else(|| AppError::BadRequest("Missing or invalid Upload-Offset".to_string()))?;
+
+    let stream = body
+        .into_data_stream()
+        .map(|chunk| chunk.map_err(io::Error::other));
+    let upload = file_manager
+        .append_upload(&server_id, &upload_id, offset, StreamReader::new(stream))
+        .await?;
+    Ok((StatusCode::NO_CONTENT, upload_headers(&upload)))
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/uploads/{upload}/finish",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("upload" = String, Path, description = "Id of the upload"),
+    ),
+    responses(
+        (status = 201, description = "The file was moved to its path"),
+        (status = 404, description = "The upload doesn't exist", body = ErrorBody),
+        (status = 409, description = "The upload is incomplete", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn finish_upload(
+    Path((server_id, upload_id)): Path<(String, String)>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<StatusCode, AppError> {
+    file_manager.finish_upload(&server_id, &upload_id).await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/servers/{id}/files/uploads/{upload}",
+    tag = "files",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("upload" = String, Path, description = "Id of the upload"),
+    ),
+    responses(
+        (status = 204, description = "The upload was discarded"),
+        (status = 404, description = "The upload doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn cancel_upload(
+    Path((server_id, upload_id)): Path<(String, String)>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<StatusCode, AppError> {
+    file_manager.cancel_upload(&server_id, &upload_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn upload_headers(upload: &UploadStatus) -> [(&'static str, String); 2] {
+    [
+        (UPLOAD_OFFSET_HEADER, upload.offset.to_string()),
+        (UPLOAD_LENGTH_HEADER, upload.size.to_string()),
+    ]
+}
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/files/download-link",
+    tag = "files",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = CreateDownloadLink,
+    responses(
+        (status = 200, body = DownloadLink),
+        (status = 400, description = "The path is a directory", body = ErrorBody),
+        (status = 403, description = "The path leaves the server directory", body = ErrorBody),
+        (status = 404, description = "The file doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(file_manager), level = "debug")]
+pub async fn create_download_link(
+    Path(server_id): Path<String>,
+    State(file_manager): State<Arc<FileManager>>,
+    Json(request): Json<CreateDownloadLink>,
+) -> Result<Json<DownloadLink>, AppError> {
+    let (token, expires) = file_manager
+        .sign_download(&server_id, &request.path, request.expires_in)
+        .await?;
+    Ok(Json(DownloadLink {
+        url: format!("/download?token={token}"),
+        expires,
+    }))
+}
+
+/// Serves the file of a download link. Not behind the API authentication, the
+/// token in the link is the credential.
+#[utoipa::path(
+    get,
+    path = "/download",
+    tag = "files",
+    params(DownloadQuery),
+    security(()),
+    responses(
+        (
+            status = 200, description = "The file, as an attachment",
+            body = Vec<u8>, content_type = "application/octet-stream"
+        ),
+        (status = 401, description = "The link is invalid or was used", body = ErrorBody),
+        (status = 404, description = "The file no longer exists", body = ErrorBody),
+    ),
+)]
+#[instrument(skip_all, level = "debug")]
+pub async fn download(
+    Query(query): Query<DownloadQuery>,
+    State(file_manager): State<Arc<FileManager>>,
+) -> Result<impl IntoResponse, AppError> {
+    let (file, size, name) = file_manager.redeem_download(&query.token).await?;
+    let mime = mime_guess::from_path(&name).first_or_octet_stream();
+    // Header values can only contain printable ASCII.
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            '"' | '\\' => '_',
+            c if c.is_ascii_graphic() || c == ' ' => c,
+            _ => '_',
+        })
+        .collect();
+    let disposition = format!("attachment; filename=\"{name}\"");
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, mime.to_string()),
+            (header::CONTENT_LENGTH, size.to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        Body::from_stream(ReaderStream::new(file)),
+    ))
+}
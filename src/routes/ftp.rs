@@ -0,0 +1,106 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use serde::Deserialize;
+use tracing::instrument;
+use utoipa::IntoParams;
+
+use super::{
+    error::ErrorBody,
+    extract::{Json, Path, Query},
+    AppError,
+};
+use crate::ftp::{
+    audit::{AuditEvent, AuditLog},
+    auth::is_valid_server_id,
+    FtpHandle, FtpHealth,
+};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Only return the events after this unix timestamp in milliseconds.
+    since: Option<u64>,
+    /// Maximum no of events to return.
+    #[serde(default = "default_limit")]
+    limit: usize,
+}
+
+fn default_limit() -> usize {
+    100
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/ftp/audit",
+    tag = "ftp",
+    params(("id" = String, Path, description = "Id of the server"), AuditQuery),
+    responses(
+        (status = 200, body = Vec<AuditEvent>),
+        (status = 400, description = "The server id is invalid", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(audit_log), level = "debug")]
+pub async fn get_audit_log(
+    Path(server_id): Path<String>,
+    Query(query): Query<AuditQuery>,
+    State(audit_log): State<Arc<AuditLog>>,
+) -> Result<Json<Vec<AuditEvent>>, AppError> {
+    if !is_valid_server_id(&server_id) {
+        return Err(AppError::BadRequest(format!(
+            "Invalid server id: {server_id}"
+        )));
+    }
+
+    let events = audit_log
+        .query(&server_id, query.since, query.limit)
+        .await?;
+    Ok(Json(events))
+}
+
+#[utoipa::path(
+    get,
+    path = "/ftp/health",
+    tag = "ftp",
+    responses((status = 200, body = FtpHealth)),
+)]
+#[instrument(skip(ftp_handle), level = "debug")]
+pub async fn get_health(State(ftp_handle): State<Arc<FtpHandle>>) -> Json<FtpHealth> {
+    Json(ftp_handle.health())
+}
+
+#[utoipa::path(
+    post,
+    path = "/ftp/start",
+    tag = "ftp",
+    responses((status = 202, description = "The server is starting, see `/ftp/health`")),
+)]
+#[instrument(skip(ftp_handle), level = "debug")]
+pub async fn start(State(ftp_handle): State<Arc<FtpHandle>>) -> Result<StatusCode, AppError> {
+    ftp_handle.start().await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/ftp/stop",
+    tag = "ftp",
+    responses((status = 202, description = "The server is stopping, see `/ftp/health`")),
+)]
+#[instrument(skip(ftp_handle), level = "debug")]
+pub async fn stop(State(ftp_handle): State<Arc<FtpHandle>>) -> Result<StatusCode, AppError> {
+    ftp_handle.stop().await?;
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[utoipa::path(
+    post,
+    path = "/ftp/restart",
+    tag = "ftp",
+    responses((status = 202, description = "The server is restarting, see `/ftp/health`")),
+)]
+#[instrument(skip(ftp_handle), level = "debug")]
+pub async fn restart(State(ftp_handle): State<Arc<FtpHandle>>) -> Result<StatusCode, AppError> {
+    ftp_handle.restart().await?;
+    Ok(StatusCode::ACCEPTED)
+}
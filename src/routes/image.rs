@@ -0,0 +1,36 @@
+use std::sync::Arc;
+
+use axum::extract::State;
+use serde::Deserialize;
+use tracing::instrument;
+use utoipa::IntoParams;
+
+use super::{
+    extract::{Json, Query},
+    AppError,
+};
+use crate::managers::{docker::GcReport, recipe::RecipeManager};
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct GcQuery {
+    /// Only report what would be removed.
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/images/gc",
+    tag = "images",
+    params(GcQuery),
+    responses((status = 200, body = GcReport)),
+)]
+#[instrument(skip(recipe_manager), level = "debug")]
+pub async fn collect_garbage(
+    Query(query): Query<GcQuery>,
+    State(recipe_manager): State<Arc<RecipeManager>>,
+) -> Result<Json<GcReport>, AppError> {
+    let report = recipe_manager.collect_image_garbage(query.dry_run).await?;
+    Ok(Json(report))
+}
@@ -0,0 +1,53 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::instrument;
+
+use crate::managers::metrics::MetricsManager;
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "system",
+    responses(
+        (
+            status = 200, description = "The metrics in the Prometheus text format",
+            body = String, content_type = "text/plain"
+        ),
+    ),
+)]
+#[instrument(skip(metrics_manager), level = "debug")]
+pub async fn get_metrics(State(metrics_manager): State<Arc<MetricsManager>>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        metrics_manager.render().await,
+    )
+}
+
+/// Records how long the requests take by route. Added as a route layer, so the
+/// route has been matched when it runs.
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_string());
+    let method = request.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        metrics::histogram!(
+            "mastiff_http_request_duration_seconds",
+            "method" => method,
+            "route" => route,
+            "status" => response.status().as_u16().to_string()
+        )
+        .record(started.elapsed().as_secs_f64());
+    }
+    response
+}
@@ -0,0 +1,103 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use tracing::instrument;
+
+use super::{
+    error::ErrorBody,
+    extract::{Json, Path},
+    AppError,
+};
+use crate::managers::{firewall::EgressPolicy, network::NetworkManager};
+
+#[utoipa::path(
+    post,
+    path = "/servers/{id}/links/{target}",
+    tag = "network",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("target" = String, Path, description = "Id of the server to link to"),
+    ),
+    responses(
+        (status = 204, description = "The servers can reach each other"),
+        (
+            status = 403, description = "Only the panel can link servers",
+            body = ErrorBody
+        ),
+        (status = 404, description = "A server doesn't exist", body = ErrorBody),
+        (status = 409, description = "The servers can't be linked", body = ErrorBody),
+    ),
+)]
+/// Only the panel knows who owns the target, so users can't link their servers.
+#[instrument(skip(network_manager), level = "debug")]
+pub async fn link_servers(
+    Path((server_id, target_id)): Path<(String, String)>,
+    State(network_manager): State<Arc<NetworkManager>>,
+) -> Result<StatusCode, AppError> {
+    network_manager.link_servers(&server_id, &target_id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/servers/{id}/links/{target}",
+    tag = "network",
+    params(
+        ("id" = String, Path, description = "Id of the server"),
+        ("target" = String, Path, description = "Id of the server to link to"),
+    ),
+    responses(
+        (status = 204, description = "The servers can no longer reach each other"),
+        (status = 404, description = "A server doesn't exist", body = ErrorBody),
+        (status = 409, description = "The servers can't be unlinked", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(network_manager), level = "debug")]
+pub async fn unlink_servers(
+    Path((server_id, target_id)): Path<(String, String)>,
+    State(network_manager): State<Arc<NetworkManager>>,
+) -> Result<StatusCode, AppError> {
+    network_manager
+        .unlink_servers(&server_id, &target_id)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/egress",
+    tag = "network",
+    params(("id" = String, Path, description = "Id of the server")),
+    responses((status = 200, body = EgressPolicy)),
+)]
+#[instrument(skip(network_manager), level = "debug")]
+pub async fn get_egress_policy(
+    Path(server_id): Path<String>,
+    State(network_manager): State<Arc<NetworkManager>>,
+) -> Json<EgressPolicy> {
+    Json(network_manager.get_egress_policy(&server_id).await)
+}
+
+#[utoipa::path(
+    put,
+    path = "/servers/{id}/egress",
+    tag = "network",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = EgressPolicy,
+    responses(
+        (status = 204, description = "The policy was applied"),
+        (status = 400, description = "The policy is invalid", body = ErrorBody),
+        (status = 404, description = "The server doesn't exist", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(network_manager), level = "debug")]
+pub async fn set_egress_policy(
+    Path(server_id): Path<String>,
+    State(network_manager): State<Arc<NetworkManager>>,
+    Json(policy): Json<EgressPolicy>,
+) -> Result<StatusCode, AppError> {
+    network_manager
+        .set_egress_policy(&server_id, policy)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
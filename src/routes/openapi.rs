@@ -0,0 +1,179 @@
+use std::{future, path::PathBuf};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{Html, IntoResponse},
+    routing::get,
+    Json, Router,
+};
+use utoipa::{
+    openapi::{
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        OpenApi as Document,
+    },
+    Modify, OpenApi,
+};
+
+use crate::managers::Managers;
+
+/// Redoc, rendering the document served at `/openapi.json`. The script is served by
+/// the node as well, so no third-party code runs on its origin.
+const DOCS_PAGE: &str = include_str!("docs.html");
+
+/// The parts of the OpenAPI document which don't belong to a route. The paths are
+/// added from the `utoipa::path` attributes of the handlers as they are routed, so
+/// the document can't list a route which doesn't exist or miss one which does.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Mastiff", description = "The API the panel manages the node with."),
+    modifiers(&SecuritySchemes),
+    security(("bearer" = []), ("signature" = [])),
+    tags(
+        (name = "recipes", description = "Installing and rolling back recipes"),
+        (name = "images", description = "Cleaning up the images built for recipes"),
+        (name = "servers", description = "Resource usage and limits of the servers"),
+        (name = "network", description = "Links between servers and egress policies"),
+        (name = "files", description = "The file manager of the server directories"),
+        (name = "ftp", description = "The FTP server and its audit log"),
+        (name = "system", description = "The node itself and its health"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// The ways of authenticating described in `routes/auth.rs`.
+struct SecuritySchemes;
+
+impl Modify for SecuritySchemes {
+    fn modify(&self, openapi: &mut Document) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .description(Some(
+                        "The node token, or a JWT the panel issued to one of its users",
+                    ))
+                    .build(),
+            ),
+        );
+        components.add_security_scheme(
+            "signature",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::with_description(
+                "x-mastiff-signature",
+                "HMAC-SHA256 of the request keyed with the node token, sent along with \
+                 the `x-mastiff-timestamp` and `x-mastiff-nonce` headers",
+            ))),
+        );
+    }
+}
+
+/// Serves the document at `/openapi.json` and, if a copy of the Redoc bundle is
+/// configured, a page rendering it at `/docs`.
+pub fn routes(document: Document, docs_bundle: Option<PathBuf>) -> Router<Managers> {
+    let router = Router::new().route(
+        "/openapi.json",
+        get(move || future::ready(Json(document.clone()))),
+    );
+    let Some(docs_bundle) = docs_bundle else {
+        return router;
+    };
+
+    router
+        .route("/docs", get(|| future::ready(Html(DOCS_PAGE))))
+        .route(
+            "/docs/redoc.standalone.js",
+            get(move || redoc_bundle(docs_bundle.clone())),
+        )
+}
+
+/// Read on every request, the page is rarely opened.
+async fn redoc_bundle(path: PathBuf) -> impl IntoResponse {
+    match tokio::fs::read(&path).await {
+        Ok(script) => Ok(([(header::CONTENT_TYPE, "text/javascript")], script)),
+        Err(e) => {
+            tracing::error!("Could not read the Redoc bundle {}: {e}", path.display());
+            Err(StatusCode::NOT_FOUND)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use crate::{config::ApiSettings, routes::api_routes};
+
+    const METHODS: [&str; 5] = ["get", "put", "post", "delete", "patch"];
+
+    fn document() -> Value {
+        let settings = ApiSettings {
+            port: 0,
+            interface: [127, 0, 0, 1].into(),
+            token: String::new(),
+            signature_max_age: 0,
+            jwt_secret: String::new(),
+            max_recipe_size: 0,
+            docs_bundle: None,
+        };
+        let (_, document) = api_routes(&settings).split_for_parts();
+        serde_json::to_value(document).unwrap()
+    }
+
+    /// The parameters are declared by hand, so they could drift from the paths.
+    #[test]
+    fn path_parameters_match_the_paths() {
+        let document = document();
+        for (path, item) in document["paths"].as_object().unwrap() {
+            let mut expected: Vec<&str> = path
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+                .collect();
+            expected.sort();
+
+            for method in METHODS.iter().filter(|method| item.get(**method).is_some()) {
+                let mut declared: Vec<&str> = item[method]["parameters"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .filter(|parameter| parameter["in"] == "path")
+                    .filter_map(|parameter| parameter["name"].as_str())
+                    .collect();
+                declared.sort();
+                assert_eq!(declared, expected, "{method} {path}");
+            }
+        }
+    }
+
+    #[test]
+    fn schema_references_resolve() {
+        fn references<'a>(value: &'a Value, found: &mut Vec<&'a str>) {
+            match value {
+                Value::Object(map) => {
+                    for (key, value) in map {
+                        match value.as_str() {
+                            Some(reference) if key == "$ref" => found.push(reference),
+                            _ => references(value, found),
+                        }
+                    }
+                }
+                Value::Array(values) => values.iter().for_each(|value| references(value, found)),
+                _ => {}
+            }
+        }
+
+        let document = document();
+        let mut found = Vec::new();
+        references(&document, &mut found);
+
+        for reference in found {
+            let name = reference
+                .strip_prefix("#/components/schemas/")
+                .unwrap_or_else(|| panic!("Unexpected reference {reference}"));
+            assert!(
+                document["components"]["schemas"].get(name).is_some(),
+                "{reference} is not in the document"
+            );
+        }
+    }
+}
@@ -1,26 +1,51 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use axum::{
     debug_handler,
-    extract::{Multipart, Path, State},
+    extract::{Multipart, State},
     http::StatusCode,
     response::IntoResponse,
 };
-use eyre::{bail, eyre, Result};
+use eyre::Result;
 use tokio::sync::OnceCell;
 use tokio_stream::StreamExt;
 use tokio_util::io::StreamReader;
 use tracing::instrument;
+use utoipa::ToSchema;
 
-use super::AppError;
+use super::{error::ErrorBody, extract::Path, AppError};
 use crate::managers::recipe::RecipeManager;
 
+/// The form of a recipe upload, only used to document the API.
+#[derive(ToSchema)]
+#[allow(dead_code)]
+struct RecipeUpload {
+    name: String,
+    /// JSON object of the arguments passed to the build.
+    build_args: Option<String>,
+    /// The compressed recipe directory.
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/recipes/upload",
+    tag = "recipes",
+    request_body(content = RecipeUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 201, description = "The recipe was installed and its image built"),
+        (status = 400, description = "The archive or the recipe is invalid", body = ErrorBody),
+        (status = 413, description = "The archive is larger than `rest_api.max_recipe_size`"),
+    ),
+)]
 #[debug_handler]
 pub async fn upload_recipe(
     State(recipe_manager): State<Arc<RecipeManager>>,
     mut multipart: Multipart,
 ) -> Result<impl IntoResponse, AppError> {
     let mut recipe_name = None;
+    let mut build_args = HashMap::new();
     let mut file_reader = OnceCell::new();
 
     while let Some(mut field) = multipart.next_field().await.unwrap() {
@@ -30,9 +55,13 @@ pub async fn upload_recipe(
             if field_name == "name" {
                 recipe_name = Some(field.text().await.unwrap());
                 continue;
+            } else if field_name == "build_args" {
+                build_args = serde_json::from_str(&field.text().await?)?;
+                continue;
             }
         } else if field.file_name().is_some() {
-            let file_stream = field.chunk().await?.unwrap();
+            // The whole file, not only the first chunk of it.
+            let file_stream = field.bytes().await?;
 
             file_reader.set(file_stream)?;
         }
@@ -44,13 +73,27 @@ pub async fn upload_recipe(
             .decompress_files(&recipe_name, file_reader as &[u8])
             .await?;
 
-        recipe_manager.build_recipe(&recipe_path).await?;
+        recipe_manager
+            .build_recipe(&recipe_path, build_args)
+            .await?;
 
         return Ok(StatusCode::CREATED);
     }
-    return Err(eyre!("Missing compressed recipe file.").into());
+    Err(AppError::BadRequest(
+        "Missing compressed recipe file.".to_string(),
+    ))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/recipes/{name}",
+    tag = "recipes",
+    params(("name" = String, Path, description = "Name of the recipe")),
+    responses(
+        (status = 204, description = "The recipe was removed"),
+        (status = 404, description = "The recipe doesn't exist", body = ErrorBody),
+    ),
+)]
 #[instrument(skip(recipe_manager), level = "debug")]
 pub async fn delete_recipe(
     Path(recipe_name): Path<String>,
@@ -59,3 +102,24 @@ pub async fn delete_recipe(
     recipe_manager.delete_recipe(&recipe_name).await?;
     Ok(StatusCode::NO_CONTENT)
 }
+
+#[utoipa::path(
+    post,
+    path = "/recipes/{name}/rollback",
+    tag = "recipes",
+    params(("name" = String, Path, description = "Name of the recipe")),
+    responses(
+        (
+            status = 200, description = "The version which is now active",
+            body = String, content_type = "text/plain"
+        ),
+        (status = 409, description = "There is no previous version", body = ErrorBody),
+    ),
+)]
+#[instrument(skip(recipe_manager), level = "debug")]
+pub async fn rollback_recipe(
+    Path(recipe_name): Path<String>,
+    State(recipe_manager): State<Arc<RecipeManager>>,
+) -> Result<String, AppError> {
+    Ok(recipe_manager.rollback_recipe(&recipe_name).await?)
+}
@@ -0,0 +1,60 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::instrument;
+use utoipa::ToSchema;
+
+use super::{
+    extract::{Json, Path},
+    AppError,
+};
+use crate::managers::quota::{DiskUsage, QuotaManager};
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ServerStats {
+    disk: DiskUsage,
+}
+
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct DiskLimit {
+    /// Limit in bytes, `None` removes the limit.
+    limit: Option<u64>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/servers/{id}/stats",
+    tag = "servers",
+    params(("id" = String, Path, description = "Id of the server")),
+    responses((status = 200, body = ServerStats)),
+)]
+#[instrument(skip(quota_manager), level = "debug")]
+pub async fn get_stats(
+    Path(server_id): Path<String>,
+    State(quota_manager): State<Arc<QuotaManager>>,
+) -> Json<ServerStats> {
+    Json(ServerStats {
+        disk: quota_manager.usage(&server_id).await,
+    })
+}
+
+#[utoipa::path(
+    put,
+    path = "/servers/{id}/disk-limit",
+    tag = "servers",
+    params(("id" = String, Path, description = "Id of the server")),
+    request_body = DiskLimit,
+    responses((status = 204, description = "The limit was set")),
+)]
+#[instrument(skip(quota_manager), level = "debug")]
+pub async fn set_disk_limit(
+    Path(server_id): Path<String>,
+    State(quota_manager): State<Arc<QuotaManager>>,
+    Json(disk_limit): Json<DiskLimit>,
+) -> Result<StatusCode, AppError> {
+    quota_manager
+        .set_limit(&server_id, disk_limit.limit)
+        .await?;
+    Ok(StatusCode::NO_CONTENT)
+}
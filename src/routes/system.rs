@@ -0,0 +1,57 @@
+use std::sync::Arc;
+
+use axum::{extract::State, http::StatusCode};
+use tracing::instrument;
+
+use super::{extract::Json, AppError};
+use crate::managers::system::{Readiness, SystemInfo, SystemManager};
+
+#[utoipa::path(
+    get,
+    path = "/system",
+    tag = "system",
+    responses((status = 200, body = SystemInfo)),
+)]
+#[instrument(skip(system_manager), level = "debug")]
+pub async fn get_system(
+    State(system_manager): State<Arc<SystemManager>>,
+) -> Result<Json<SystemInfo>, AppError> {
+    Ok(Json(system_manager.info().await?))
+}
+
+/// Answers as long as the daemon is running, for liveness checks.
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "system",
+    security(()),
+    responses((status = 204, description = "The daemon is running")),
+)]
+pub async fn health() -> StatusCode {
+    StatusCode::NO_CONTENT
+}
+
+/// Whether the node can take on servers, for load balancers and the panel's node
+/// monitor.
+#[utoipa::path(
+    get,
+    path = "/ready",
+    tag = "system",
+    security(()),
+    responses(
+        (status = 200, description = "The node is ready", body = Readiness),
+        (status = 503, description = "Docker or the FTP server is down", body = Readiness),
+    ),
+)]
+#[instrument(skip(system_manager), level = "debug")]
+pub async fn ready(
+    State(system_manager): State<Arc<SystemManager>>,
+) -> (StatusCode, Json<Readiness>) {
+    let readiness = system_manager.readiness().await;
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}